@@ -1,107 +1,114 @@
+extern crate similar;
+extern crate libc;
+
 use clap::{ArgMatches};
 
-use std::path::PathBuf;
-use std::collections::{HashSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::collections::{HashSet, HashMap, BTreeSet};
 
 use std::fmt;
-use std::io::{self, BufRead};
 use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
 
-use templates::Templates;
+use templates::{self, Templates};
 use file_utils::FileUtils;
+use meta::{Meta, Entry, LinkMode};
+use journal::Record;
+use backups::Backups;
+use config::Config;
+use crypt::Crypt;
 
 use snafu::*;
 use errors::*;
 
-struct Meta {
-    dry: bool,
-    meta_file: PathBuf,
-    entries: Vec<String>,
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Group {
+    root: PathBuf,
+    dir: PathBuf,
 }
 
-impl Meta {
-    fn new(group: &Group) -> Result<Self> {
-        let meta_file = group.abs_path().join("meta.txt");
-        let entries = if meta_file.exists() {
-            io::BufReader::new(fs::File::open(PathBuf::from(&meta_file)).context(IoError {path: meta_file.clone()})?)
-                .lines()
-                .map(|l| l.unwrap())
-                .collect::<Vec<_>>()
-        }
-        else {
-            Vec::new()
-        };
-        let dry = group.dry;
-        Ok(Self {dry, meta_file, entries})
-    }
-    fn add(&mut self, entry: &PathBuf) -> Result<()> {
-        let meta_file = &self.meta_file;
-        trace!("{:?} add {:?}", meta_file, entry);
-        let entry_str = entry.to_str().unwrap().to_string();
-        if ! meta_file.exists() {
-            let entry_str = entry.to_str().unwrap().to_string();
-            fs::write(&meta_file, entry_str + "\n").context(IoError { path: meta_file })?;
-            return Ok(());
-        }
-        let mut lines = self.list()?;
-        let len_before = lines.len();
-        lines.push(entry_str);
-        lines.sort();
-        lines.dedup();
-        if lines.len() == len_before {
-            trace!("no new entries for meta");
-            return Ok(());
+impl Group {
+    fn new(root: PathBuf, path: &str) -> Result<Self> {
+        // dirs of storage itself, and hidden ones like .git
+        if path.is_empty() || path.find('/').is_some() || path.starts_with('.') || ["backup", "journal", "tune"].contains(&path) {
+            misc_error_file!("Invalid group name", PathBuf::from(path))
         }
-        self.entries = lines;
-        self.save()?;
-
-        Ok(())
+        Ok(Group { dir: PathBuf::from(path), root, })
     }
-    fn delete(&mut self, entry: &PathBuf) -> Result<()> {
-        let entries = self.entries.clone().into_iter().filter(|s| PathBuf::from(s) != *entry).collect::<Vec<_>>();
-        self.entries = entries;
-        self.save()?;
-
-        Ok(())
+    fn add_meta(&self, entry: Entry, fs: &FileUtils) -> Result<()> {
+        let mut meta = Meta::new(self, fs)?;
+        meta.add(entry)
     }
-    fn save(&self) -> Result<()> {
-        trace!("new meta: {:?}", self.entries);
-        if self.dry {
-            return Ok(())
-        }
-        fs::write(&self.meta_file, self.entries.join("\n") + "\n").context(IoError {path: &self.meta_file})?;
-
-        Ok(())
+    pub fn abs_path(&self) -> PathBuf {
+        self.root.join(self.dir.clone())
+    }
+    fn rel_entry(entry: &Path) -> PathBuf {
+        // absolute entries (/etc/hosts) are stored under group dir as etc/hosts
+        entry.strip_prefix("/").unwrap_or(entry).to_path_buf()
     }
-    fn list(&self) -> Result<Vec<String>> {
-        Ok(self.entries.clone())
+    fn src_path(&self, entry: &Path) -> PathBuf {
+        self.abs_path().join(Self::rel_entry(entry))
     }
-    fn check(&self, entry: &PathBuf) -> bool {
-        // is entry in meta.txt?
-        self.entries.iter().any(|ref e| &PathBuf::from(e) == entry)
+    fn template_name(&self, entry: &Path) -> PathBuf {
+        self.dir.join(Self::rel_entry(entry))
     }
 }
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone)]
-struct Group {
-    dry: bool,
-    root: PathBuf,
-    dir: PathBuf,
+#[derive(Debug, PartialEq)]
+enum LinkStatus {
+    Linked,
+    Copied,
+    LinkedElsewhere(PathBuf),
+    Unlinked,
+    MissingHome,
+    MissingSource,
+    Stale,
 }
 
-impl Group {
-    fn new(dry: bool, root: PathBuf, path: &str) -> Result<Self> {
-        if path.find('/').is_some() || path == "backup" {
-            misc_error_file!("Invalid group name", PathBuf::from(path))
+impl fmt::Display for LinkStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            LinkStatus::Linked => write!(f, "linked"),
+            LinkStatus::Copied => write!(f, "copied"),
+            LinkStatus::LinkedElsewhere(_) => write!(f, "linked elsewhere"),
+            LinkStatus::Unlinked => write!(f, "not a link"),
+            LinkStatus::MissingHome => write!(f, "missing in home"),
+            LinkStatus::MissingSource => write!(f, "missing in group"),
+            LinkStatus::Stale => write!(f, "stale template"),
         }
-        Ok(Group { dry, dir: PathBuf::from(path), root, })
     }
-    fn add_meta(&self, entry: &PathBuf) -> Result<()> {
-        let mut meta = Meta::new(&self)?;
-        meta.add(entry)
+}
+
+// what link does with a real file where the link should be
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OnConflict {
+    Backup,
+    Skip,
+    Adopt,
+    Fail,
+}
+
+impl OnConflict {
+    fn parse(s: &str) -> Result<Self> {
+        match s {
+            "backup" => Ok(OnConflict::Backup),
+            "skip" => Ok(OnConflict::Skip),
+            "adopt" => Ok(OnConflict::Adopt),
+            "fail" => Ok(OnConflict::Fail),
+            _ => misc_error!(format!("invalid --on-conflict value: {}", s)),
+        }
     }
-    fn abs_path(&self) -> PathBuf {
-        self.root.join(self.dir.clone())
+}
+
+impl fmt::Display for OnConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            OnConflict::Backup => write!(f, "backup"),
+            OnConflict::Skip => write!(f, "skip"),
+            OnConflict::Adopt => write!(f, "adopt"),
+            OnConflict::Fail => write!(f, "fail"),
+        }
     }
 }
 
@@ -117,14 +124,20 @@ pub struct Confine {
     home: PathBuf,
     root: PathBuf,
     templates: Templates,
+    backups: Backups,
+    crypt: Crypt,
     groups: HashMap<String, Group>,
     template: Option<String>,
     del_link_only: bool,
+    yes: bool,
+    encrypt: bool,
+    interactive: bool,
+    on_conflict: OnConflict,
     fs: FileUtils,
 }
 
 impl Confine {
-    pub fn new(matches: &ArgMatches) -> Self {
+    pub fn new(matches: &ArgMatches) -> Result<Self> {
         let dry = matches.is_present("dry");
         let quiet = matches.is_present("quiet") && ! dry;
         let trace = matches.is_present("trace");
@@ -133,16 +146,25 @@ impl Confine {
         let root = PathBuf::from(matches.value_of("root").unwrap()).canonicalize().unwrap();
         let home = matches.value_of("home").map_or(dirs::home_dir().unwrap(), |p| PathBuf::from(p).canonicalize().unwrap());
 
-        Self {
+        let fs = FileUtils::new(dry);
+        let config = Config::load(&root)?;
+
+        Ok(Self {
             dry: dry,
-            templates: Templates::new(root.clone(), home.clone()),
+            templates: Templates::new(root.clone(), home.clone(), config.templates.clone(), matches.is_present("strict"), fs.clone()),
+            backups: Backups::new(root.clone(), home.clone(), fs.clone(), config.backup.keep),
+            crypt: Crypt::new(&home, config.encryption.key.as_ref(), dry),
             home: home,
             root: root,
             groups: HashMap::new(),
             template: None,
             del_link_only: false,
-            fs: FileUtils::new(dry),
-        }
+            yes: false,
+            encrypt: false,
+            interactive: false,
+            on_conflict: OnConflict::Backup,
+            fs,
+        })
     }
 
     fn init_logger(quiet: bool, trace: bool) {
@@ -157,85 +179,259 @@ impl Confine {
     }
 
     pub fn run(&mut self, matches: &ArgMatches) -> Result<()> {
+        // all or nothing: on error undo everything done so far
+        let result = self.run_command(matches).and_then(|_| self.fs.commit());
+        // the original error is what user needs to see, rollback logs its own failures
+        if result.is_err() && self.fs.rollback().is_err() {
+            error!("rollback is incomplete, see errors above");
+        }
+        result
+    }
+
+    fn run_command(&mut self, matches: &ArgMatches) -> Result<()> {
+        if ["move", "link", "undo", "delete", "adopt", "group", "rollback", "restore-backup"].iter().any(|c| matches.subcommand_matches(c).is_some()) {
+            let command = std::env::args().skip(1).collect::<Vec<_>>().join(" ");
+            self.fs.persist(self.journal_dir(), command, hostname());
+        }
+
         if let Some(matches) = matches.subcommand_matches("link") {
            let (files, group) = self.get_files_from_args(&matches)?;
-           self.template = matches.value_of("template").map(|s| s.to_string());
+           self.select_template(matches.value_of("template"));
+           self.interactive = matches.is_present("interactive");
+           self.on_conflict = OnConflict::parse(matches.value_of("on-conflict").unwrap())?;
            self.link_files(group, files)
         }
         else if let Some(matches) = matches.subcommand_matches("move") {
            let (files, group) = self.get_files_from_args(&matches)?;
+           self.encrypt = matches.is_present("encrypt");
            self.move_files(group, files)
         }
         else if let Some(matches) = matches.subcommand_matches("undo") {
             let (files, group) = self.get_files_from_args(&matches)?;
+            self.select_template(matches.value_of("template"));
             self.undo_files(group, files)
         }
         else if let Some(matches) = matches.subcommand_matches("delete") {
             let (files, group) = self.get_files_from_args(&matches)?;
             self.del_link_only = matches.is_present("link");
+            self.yes = matches.is_present("yes");
             self.delete_files(group, files)
         }
+        else if let Some(matches) = matches.subcommand_matches("status") {
+            let groups = self.groups_from_args(matches)?;
+            self.status_files(groups)
+        }
+        else if let Some(matches) = matches.subcommand_matches("adopt") {
+            let groups = self.groups_from_args(matches)?;
+            self.yes = matches.is_present("yes");
+            self.adopt_files(groups, matches.is_present("diff"))
+        }
+        else if let Some(matches) = matches.subcommand_matches("diff") {
+            let groups = self.groups_from_args(matches)?;
+            self.select_template(matches.value_of("template"));
+            self.diff_files(groups)
+        }
+        else if let Some(matches) = matches.subcommand_matches("backups") {
+            if let Some(matches) = matches.subcommand_matches("prune") {
+                let keep = match matches.value_of("keep") {
+                    Some(keep) => match keep.parse() {
+                        Ok(keep) => keep,
+                        Err(_) => misc_error!(format!("invalid number: {}", keep)),
+                    },
+                    None => self.backups.default_keep(),
+                };
+                let hosts = match matches.value_of("host") {
+                    Some(host) => vec![host.to_string()],
+                    None => self.backups.hosts()?,
+                };
+                for host in hosts {
+                    self.backups.prune(&host, keep, None)?;
+                }
+                Ok(())
+            }
+            else {
+                self.list_backups(matches.value_of("host"))
+            }
+        }
+        else if let Some(matches) = matches.subcommand_matches("restore-backup") {
+            let host = matches.value_of("host").map_or_else(hostname, |h| h.to_string());
+            for path in matches.values_of("files").unwrap() {
+                self.backups.restore(&PathBuf::from(path), &host, matches.value_of("generation"), &self.crypt)?;
+            }
+            Ok(())
+        }
+        else if let Some(matches) = matches.subcommand_matches("template") {
+            if let Some(matches) = matches.subcommand_matches("vars") {
+                self.inspect_template(matches.value_of("template"));
+                let (group, file) = self.get_group_file(matches.value_of("file").unwrap())?;
+                self.template_vars(&group, &file)
+            }
+            else if matches.subcommand_matches("refresh").is_some() {
+                for template_name in self.templates.refresh(None)? {
+                    println!("refreshed {}", template_name.display());
+                }
+                Ok(())
+            }
+            else if matches.subcommand_matches("check").is_some() {
+                self.check_templates()
+            }
+            else if let Some(matches) = matches.subcommand_matches("render") {
+                self.inspect_template(matches.value_of("template"));
+                let (group, file) = self.get_group_file(matches.value_of("file").unwrap())?;
+                self.render_template(&group, &file, matches.is_present("diff"))
+            }
+            else {
+                misc_error!("Subcommand missing")
+            }
+        }
+        else if let Some(matches) = matches.subcommand_matches("group") {
+            if let Some(matches) = matches.subcommand_matches("new") {
+                self.new_group(matches.value_of("name").unwrap())
+            }
+            else if matches.subcommand_matches("list").is_some() {
+                self.list_groups()
+            }
+            else if let Some(matches) = matches.subcommand_matches("rename") {
+                self.rename_group(matches.value_of("name").unwrap(), matches.value_of("new_name").unwrap())
+            }
+            else if let Some(matches) = matches.subcommand_matches("rm") {
+                self.yes = matches.is_present("yes");
+                self.remove_group(matches.value_of("name").unwrap())
+            }
+            else {
+                misc_error!("Subcommand missing")
+            }
+        }
+        else if let Some(matches) = matches.subcommand_matches("history") {
+            self.history(matches.is_present("verbose"))
+        }
+        else if let Some(matches) = matches.subcommand_matches("rollback") {
+            let id = matches.value_of("id").unwrap();
+            match id.parse() {
+                Ok(id) => self.rollback(id),
+                Err(_) => misc_error!(format!("invalid journal id: {}", id)),
+            }
+        }
         else {
             return misc_error!("Subcommand missing")
         }
     }
 
+    fn select_template(&mut self, template: Option<&str>) {
+        // control file from tune/confine.toml rules wins over -t
+        let template = template.map(|s| s.to_string());
+        self.template = match self.templates.select() {
+            Some(selected) => {
+                if template.as_ref().is_some_and(|t| *t != selected) {
+                    warn!("control file {} is selected for this host, -t {} ignored", selected, template.unwrap());
+                }
+                Some(selected)
+            }
+            None => template,
+        };
+    }
+
+    fn inspect_template(&mut self, template: Option<&str>) {
+        // commands that only show something can look at any control file, so -t wins there
+        match template {
+            Some(template) => self.template = Some(template.to_string()),
+            None => self.select_template(None),
+        }
+    }
+
     fn link_files(&mut self, group: Group, files: Vec<PathBuf>) -> Result<()> {
-        let meta = Meta::new(&group)?;
+        let meta = Meta::new(&group, &self.fs)?;
         let files = if ! files.is_empty() {
             files
         }
         else {
             meta.list()?.into_iter().map(PathBuf::from).collect()
         };
+        let hostname = hostname();
         for file in files {
             debug!("link [{}] {}", group, file.display());
-            if ! meta.check(&file) {
-                misc_error_file!("File not in meta.txt", file.clone())
+            let entry = match meta.get(&file) {
+                Some(entry) => entry.clone(),
+                None => misc_error_file!("File not in meta", file.clone()),
+            };
+            if ! entry.applies_to(&hostname) {
+                debug!("{} is not for host {}, skip", file.display(), hostname);
+                continue;
             }
-            self.link_file(&group, &file)?;
+            self.link_file(&group, &entry)?;
         }
         Ok(())
     }
-    fn link_file(&mut self, group: &Group, file: &PathBuf) -> Result<()> {
-        let template_name = group.dir.join(&file);
+    fn link_file(&mut self, group: &Group, entry: &Entry) -> Result<()> {
+        let file = &entry.path;
+        let template_name = group.template_name(file);
         
-        let src = if self.templates.needs_template(&template_name) {
+        let src = if entry.encrypted {
+            if entry.template {
+                misc_error_file!("encrypted templates are not supported", file.to_path_buf())
+            }
+            let content = self.crypt.decrypt(&group.src_path(file))?;
+            self.templates.write_processed(&template_name, &content, true)?
+        }
+        else if entry.template || self.templates.needs_template(&template_name)? {
             if self.template.is_none() {
                 // self.template is arg to -t <template>
                 misc_error_file!("Template required for file", file.to_path_buf())
             }
             else {
-                self.templates.process(&template_name, &group.abs_path().join(file), &self.template.clone().unwrap())?
+                self.templates.process(&template_name, &group.src_path(file), &self.template.clone().unwrap())?
             }
         }
         else {
-            group.abs_path().join(file)
+            group.src_path(file)
         };
         
-        let dest = if file.is_relative() {
-            self.home.join(file)
-        }
-        else {
-            return misc_error!("absolute paths are not supported yet")
-        };
-        if dest.exists() {
-            let destd = dest.display();
-            warn!("link: destination file {} exists", destd);
-            if self.fs.is_symlink(&dest)? {
-                let dest_canon = dest.canonicalize().context(IoError { path: dest.clone() })?;
-                if dest_canon == src {
-                    warn!("{} is already a link to {}", destd, src.display());
-                    return Ok(());
-                }
+        let dest = self.dest_path(entry);
+        let destd = dest.display();
+        match self.link_status(&src, &dest, entry.link)? {
+            LinkStatus::Linked => {
+                warn!("{} is already a link to {}", destd, src.display());
+                return Ok(());
+            }
+            LinkStatus::Copied => {
+                warn!("{} is already a copy of {}", destd, src.display());
+                return Ok(());
+            }
+            LinkStatus::LinkedElsewhere(_) => {
                 warn!("link: destination file {} is symlink, removing", destd);
                 self.fs.unlink(&dest)?;
             }
-            else {
+            LinkStatus::Unlinked => {
+                warn!("link: destination file {} exists", destd);
+                match self.resolve_conflict(group, entry, &dest)? {
+                    OnConflict::Skip => {
+                        warn!("{} is kept, not linked", destd);
+                        return Ok(());
+                    }
+                    OnConflict::Fail => misc_error_file!("destination file exists", dest.clone()),
+                    OnConflict::Adopt => {
+                        // group gets home version, home one goes to backup like with overwrite
+                        self.take_home_file(group, entry, &dest)?;
+                        if entry.encrypted {
+                            // decrypted copy is of the old version
+                            let content = self.crypt.decrypt(&group.src_path(file))?;
+                            self.templates.write_processed(&template_name, &content, true)?;
+                        }
+                    }
+                    OnConflict::Backup => (),
+                }
                 warn!("creating backup for {} before overwriting", destd);
-                self.backup_file(&group, &dest)?;
-                self.fs.unlink(&dest)?;
+                if entry.encrypted {
+                    // no plaintext of encrypted entries is kept in storage
+                    self.backups.backup(&dest, Some(&self.crypt))?;
+                    self.fs.unlink_private(&dest)?;
+                }
+                else {
+                    self.backups.backup(&dest, None)?;
+                    self.fs.unlink(&dest)?;
+                }
             }
+            _ => {}
         }
         let link_dir = dest.parent().unwrap();
         if ! link_dir.exists() {
@@ -247,26 +443,263 @@ impl Confine {
             misc_error_file!("Source file not found", src.clone())
         }
 
-        self.fs.symlink(&src, &dest)?;
+        let mode = entry.mode_bits()?;
+        if entry.link == LinkMode::Copy {
+            self.fs.copy_to(&src, &dest)?;
+            if let Some(mode) = mode {
+                self.fs.chmod(&dest, mode)?;
+            }
+        }
+        else {
+            self.fs.symlink(&src, &dest)?;
+            if let Some(mode) = mode {
+                self.fs.chmod(&src, mode)?;
+            }
+        }
 
         Ok(())
     }
 
-    fn backup_file(&self, group: &Group, path: &PathBuf) -> Result<()> {
-        let path = path.canonicalize().context(IoError {path: path})?;
-        let rel_path = path.strip_prefix(self.home.clone()).context(StripPrefixError {path: path.clone(), prefix: self.home.clone() })?.to_owned();
-        let hostname = hostname::get_hostname().unwrap();
-        let backup_dest = group.root.join("backup").join(&hostname).join(&rel_path).parent().unwrap().to_owned();
-        
-        self.fs.mkpath(&backup_dest)?;
-        
-        trace!("backup {:?} to {:?}", path, backup_dest);
+    fn resolve_conflict(&mut self, group: &Group, entry: &Entry, dest: &Path) -> Result<OnConflict> {
+        // --on-conflict, or what user chooses with --interactive
+        let is_template = entry.template || self.templates.needs_template(&group.template_name(&entry.path))?;
+        if ! self.interactive {
+            if is_template && self.on_conflict == OnConflict::Adopt {
+                misc_error_file!("can not adopt processed content of template", dest.to_path_buf())
+            }
+            return Ok(self.on_conflict);
+        }
+        let question = if is_template {
+            format!("{} exists: [o]verwrite with backup, show [d]iff, [s]kip, [a]bort?", dest.display())
+        }
+        else {
+            format!("{} exists: [o]verwrite with backup, [k]eep home version, show [d]iff, [s]kip, [a]bort?", dest.display())
+        };
+        loop {
+            let answer = match self.ask(&question)? {
+                Some(answer) => answer,
+                None => {
+                    warn!("not asking without terminal, using --on-conflict {}", self.on_conflict);
+                    return Ok(self.on_conflict);
+                }
+            };
+            match answer.as_str() {
+                "o" => return Ok(OnConflict::Backup),
+                "k" if ! is_template => return Ok(OnConflict::Adopt),
+                "s" => return Ok(OnConflict::Skip),
+                "a" => return Ok(OnConflict::Fail),
+                "d" => {
+                    self.diff_entry(group, entry)?;
+                }
+                _ => (),
+            }
+        }
+    }
 
-        self.fs.copy(&path, &backup_dest)?;
+    fn link_status(&self, src: &PathBuf, dest: &PathBuf, mode: LinkMode) -> Result<LinkStatus> {
+        // state of dest in home with respect to src in group (or processed template)
+        if fs::symlink_metadata(dest).is_err() {
+            return Ok(LinkStatus::MissingHome);
+        }
+        if ! self.fs.is_symlink(dest)? {
+            if mode == LinkMode::Copy && same_content(src, dest)? {
+                return Ok(LinkStatus::Copied);
+            }
+            return Ok(LinkStatus::Unlinked);
+        }
+        match dest.canonicalize() {
+            Ok(ref dest_canon) if dest_canon == src => Ok(LinkStatus::Linked),
+            Ok(dest_canon) => Ok(LinkStatus::LinkedElsewhere(dest_canon)),
+            // dangling symlink
+            Err(_) => Ok(LinkStatus::LinkedElsewhere(fs::read_link(dest).context(IoError {path: dest.clone()})?)),
+        }
+    }
 
+    fn host_entries(&self, group: &Group, files: Vec<PathBuf>) -> Result<Vec<Entry>> {
+        // meta entries of files (all if none given) that apply to this host
+        let meta = Meta::new(group, &self.fs)?;
+        let files = if ! files.is_empty() {
+            files
+        }
+        else {
+            meta.list()?.into_iter().map(PathBuf::from).collect()
+        };
+        let hostname = hostname();
+        let mut entries = Vec::new();
+        for file in files {
+            let entry = match meta.get(&file) {
+                Some(entry) => entry.clone(),
+                None => misc_error_file!("File not in meta", file.clone()),
+            };
+            if ! entry.applies_to(&hostname) {
+                debug!("{} is not for host {}, skip", file.display(), hostname);
+                continue;
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn status_files(&mut self, groups: Vec<(Group, Vec<PathBuf>)>) -> Result<()> {
+        let mut out_of_sync = 0;
+        for (group, files) in groups {
+            for entry in self.host_entries(&group, files)? {
+                let file = &entry.path;
+                let status = self.file_status(&group, &entry)?;
+                let name = group.template_name(file);
+                match status {
+                    LinkStatus::Linked | LinkStatus::Copied => println!("{:<18} {}", status, name.display()),
+                    LinkStatus::LinkedElsewhere(ref target) => {
+                        out_of_sync += 1;
+                        println!("{:<18} {} -> {}", status, name.display(), target.display())
+                    }
+                    _ => {
+                        out_of_sync += 1;
+                        println!("{:<18} {}", status, name.display())
+                    }
+                }
+            }
+        }
+        if out_of_sync > 0 {
+            misc_error!(format!("{} file(s) out of sync", out_of_sync))
+        }
         Ok(())
     }
-    fn move_files(&self, group: Group, files: Vec<PathBuf>) -> Result<()> {
+
+    fn adopt_files(&mut self, groups: Vec<(Group, Vec<PathBuf>)>, diff: bool) -> Result<()> {
+        for (group, files) in groups {
+            for entry in self.host_entries(&group, files)? {
+                self.adopt_file(&group, &entry, diff)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn adopt_file(&mut self, group: &Group, entry: &Entry, diff: bool) -> Result<()> {
+        // reverse of link: home file replaced the link, take its content into group and link again
+        let dest = self.dest_path(entry);
+        match fs::symlink_metadata(&dest) {
+            Ok(m) if ! m.file_type().is_symlink() => (),
+            _ => {
+                debug!("{} is a link or missing, skip", dest.display());
+                return Ok(());
+            }
+        }
+        let src = group.src_path(&entry.path);
+        if entry.template || self.templates.needs_template(&group.template_name(&entry.path))? {
+            warn!("{} is a template, can not adopt processed content, edit {} instead", dest.display(), src.display());
+            return Ok(());
+        }
+        if diff && self.diff_entry(group, entry)? > 0 && ! self.confirm(&format!("adopt {}?", dest.display()))? {
+            return Ok(());
+        }
+        debug!("adopt [{}] {}", group, entry.path.display());
+        self.take_home_file(group, entry, &dest)?;
+        if entry.link == LinkMode::Copy {
+            // home file is a copy anyway
+            return Ok(());
+        }
+        self.link_file(group, entry)
+    }
+
+    fn take_home_file(&mut self, group: &Group, entry: &Entry, dest: &PathBuf) -> Result<()> {
+        // replace group file with home one
+        let src = group.src_path(&entry.path);
+        if entry.encrypted {
+            let content = fs::read(dest).context(IoError {path: dest.clone()})?;
+            let armored = self.crypt.encrypt(&content)?;
+            self.fs.write(&src, &armored)?;
+        }
+        else {
+            if fs::symlink_metadata(&src).is_ok() {
+                self.fs.unlink(&src)?;
+            }
+            self.fs.copy_to(dest, &src)?;
+        }
+        Ok(())
+    }
+
+    fn diff_files(&mut self, groups: Vec<(Group, Vec<PathBuf>)>) -> Result<()> {
+        // home files that are real files or dirs instead of links, against their group version
+        let mut differ = 0;
+        for (group, files) in groups {
+            for entry in self.host_entries(&group, files)? {
+                differ += self.diff_entry(&group, &entry)?;
+            }
+        }
+        if differ > 0 {
+            misc_error!(format!("{} file(s) differ", differ))
+        }
+        Ok(())
+    }
+
+    fn diff_entry(&mut self, group: &Group, entry: &Entry) -> Result<usize> {
+        // number of files that differ
+        let dest = self.dest_path(entry);
+        match fs::symlink_metadata(&dest) {
+            Ok(m) if ! m.file_type().is_symlink() => (),
+            _ => {
+                debug!("{} is a link or missing, skip", dest.display());
+                return Ok(0);
+            }
+        }
+        let src = group.src_path(&entry.path);
+        let name = group.template_name(&entry.path);
+        if dest.is_dir() {
+            let mut files = BTreeSet::new();
+            walk_files(&src, &src, &mut files)?;
+            walk_files(&dest, &dest, &mut files)?;
+            let mut differ = 0;
+            for file in files {
+                let (old, new) = (src.join(&file), dest.join(&file));
+                let old_content = if old.is_file() { Some(fs::read(&old).context(IoError {path: old.clone()})?) } else { None };
+                let new_content = if new.is_file() { Some(fs::read(&new).context(IoError {path: new.clone()})?) } else { None };
+                differ += print_diff(old_content, new_content, &name.join(&file).display().to_string(), &new.display().to_string()) as usize;
+            }
+            return Ok(differ);
+        }
+        let (stored, stored_name) = if entry.encrypted {
+            (self.crypt.decrypt(&src)?, format!("{} (decrypted)", name.display()))
+        }
+        else if entry.template || self.templates.needs_template(&name)? {
+            let control = self.template.clone().or(self.templates.last_processed(&name)?.map(|p| p.control));
+            match control {
+                Some(control) => (self.templates.render(&name, &src, &control)?.into_bytes(), format!("{} ({})", name.display(), control)),
+                None => {
+                    warn!("{} is a template, but no template file given, comparing with it as is", name.display());
+                    (fs::read(&src).context(IoError {path: src.clone()})?, name.display().to_string())
+                }
+            }
+        }
+        else {
+            (fs::read(&src).context(IoError {path: src.clone()})?, name.display().to_string())
+        };
+        let home = fs::read(&dest).context(IoError {path: dest.clone()})?;
+        Ok(print_diff(Some(stored), Some(home), &stored_name, &dest.display().to_string()) as usize)
+    }
+
+    fn file_status(&mut self, group: &Group, entry: &Entry) -> Result<LinkStatus> {
+        let file = &entry.path;
+        let template_name = group.template_name(file);
+        let group_file = group.src_path(file);
+        if fs::symlink_metadata(&group_file).is_err() {
+            return Ok(LinkStatus::MissingSource);
+        }
+        let is_template = entry.template || self.templates.needs_template(&template_name)?;
+        let src = if is_template || entry.encrypted {
+            self.templates.processed_path(&template_name)
+        }
+        else {
+            group_file
+        };
+        let dest = self.dest_path(entry);
+        match self.link_status(&src, &dest, entry.link)? {
+            LinkStatus::Linked | LinkStatus::Copied if is_template && self.templates.is_stale(&template_name)? => Ok(LinkStatus::Stale),
+            status => Ok(status),
+        }
+    }
+
+    fn move_files(&mut self, group: Group, files: Vec<PathBuf>) -> Result<()> {
         for file in files {
             debug!("move [{}] {}", group, file.display());
             self.move_file(&group, &file)?;
@@ -274,7 +707,7 @@ impl Confine {
         Ok(())
     }
     
-    fn move_file(&self, group: &Group, file: &PathBuf) -> Result<()> {
+    fn move_file(&mut self, group: &Group, file: &PathBuf) -> Result<()> {
         let file = if file.is_relative() {
             self.home.join(file)
         }
@@ -289,7 +722,7 @@ impl Confine {
             else - save absolute path, e.g. /etc/bash/bashrc
             in later case create full path under group:
             common/etc/bash/bashrc
-            meta.txt:
+            meta:
             /etc/bash/bashrc
         */
 
@@ -308,14 +741,36 @@ impl Confine {
             misc_error!(format!("Can not move file {} to {} ({}): file exists", file.display(), group, dest.display()))
         }
 
+        if self.encrypt {
+            return self.encrypt_file(group, &file, &dest, &PathBuf::from(meta_entry));
+        }
+
         self.do_move_file(&file, &dest)?;
         if ! self.dry {
-            group.add_meta(&rel_path)?;
+            group.add_meta(Entry::new(&PathBuf::from(meta_entry)), &self.fs)?;
         }
 
         Ok(())
     }
 
+    fn encrypt_file(&mut self, group: &Group, from: &PathBuf, to: &PathBuf, meta_entry: &Path) -> Result<()> {
+        // like do_move_file, but group gets ciphertext and link points to decrypted copy
+        if ! from.is_file() {
+            misc_error_file!("only files can be encrypted", from.clone())
+        }
+        let content = fs::read(from).context(IoError {path: from.clone()})?;
+        let encrypted = self.crypt.encrypt(&content)?;
+        self.fs.mkpath(&to.parent().unwrap().to_owned())?;
+        self.fs.write(to, &encrypted)?;
+        self.fs.unlink_private(from)?;
+        if ! self.dry {
+            let entry = Entry {encrypted: true, ..Entry::new(meta_entry)};
+            group.add_meta(entry.clone(), &self.fs)?;
+            self.link_file(group, &entry)?;
+        }
+        Ok(())
+    }
+
     fn do_move_file(&self, from: &PathBuf, to: &PathBuf) -> Result<()> {
         let dest_dir = to.parent().unwrap().to_owned();
         trace!("dest dir = {:?}", dest_dir);
@@ -328,34 +783,21 @@ impl Confine {
         Ok(())
     }
 
-    fn undo_files(&self, group: Group, files: Vec<PathBuf>) -> Result<()> {
-        let meta = Meta::new(&group)?;
-        let files = if ! files.is_empty() {
-            files
-        }
-        else {
-            meta.list()?.into_iter().map(PathBuf::from).collect()
-        };
-        for file in files {
-            debug!("undo link [{}] {}", group, file.display());
-            if ! meta.check(&file) {
-                misc_error_file!("file not in meta.txt", file.clone())
-            }
-            self.undo_link_file(&group, &file)?;
+    fn undo_files(&mut self, group: Group, files: Vec<PathBuf>) -> Result<()> {
+        for entry in self.host_entries(&group, files)? {
+            debug!("undo link [{}] {}", group, entry.path.display());
+            self.undo_link_file(&group, &entry)?;
         }
         Ok(())
     }
     
-    fn undo_link_file(&self, _group: &Group, file: &PathBuf) -> Result<()> {
+    fn undo_link_file(&mut self, group: &Group, entry: &Entry) -> Result<()> {
         // before: ~/.foo.rc -> ~/config/grp/.foo.rc
         // after: ~/.foo.rc (copied from ~/config/grp/.foo.rc)
+        // for templates linked to the group file (e.g. right after move), processed content is
+        // used if it's known how to process them
 
-        let link_file = if file.is_relative() {
-            self.home.join(file)
-        }
-        else {
-            return misc_error!("absolute paths are not supported yet")
-        };
+        let link_file = self.dest_path(entry);
         if ! link_file.exists() {
             // TODO just warning?
             return misc_error_file!("file does not exists, can not undo", link_file)
@@ -367,13 +809,25 @@ impl Confine {
 
         let real_file = link_file.canonicalize().context(IoError {path: link_file.clone()})?;
 
+        let template_name = group.template_name(&entry.path);
+        if real_file == group.src_path(&entry.path).canonicalize().context(IoError {path: group.src_path(&entry.path)})?
+            && (entry.template || self.templates.needs_template(&template_name)?) {
+            let control = self.template.clone().or(self.templates.last_processed(&template_name)?.map(|p| p.control));
+            if let Some(control) = control {
+                let rendered = self.templates.render(&template_name, &real_file, &control)?;
+                self.fs.unlink(&link_file)?;
+                return self.fs.write(&link_file, &rendered);
+            }
+            warn!("{} is a template, but no template file given, restoring it as is", link_file.display());
+        }
+
         self.fs.unlink(&link_file)?;
-        self.fs.copy(&real_file, &link_file)?;
+        self.fs.copy_to(&real_file, &link_file)?;
         
         Ok(())
     }
     
-    fn delete_files(&self, group: Group, files: Vec<PathBuf>) -> Result<()> {
+    fn delete_files(&mut self, group: Group, files: Vec<PathBuf>) -> Result<()> {
         if files.is_empty() {
             warn!("No files specified. Not deleting whole group. Please do `confine undo` and remove whole group directory by hand if you don't need it anymore");
             return Ok(());
@@ -385,21 +839,17 @@ impl Confine {
         Ok(())
     }
     
-    fn delete_file(&self, group: &Group, file: &PathBuf) -> Result<()> {
+    fn delete_file(&mut self, group: &Group, file: &PathBuf) -> Result<()> {
         // 1. delete link
         // 2. delete file
-        //   2.1 delete processed template (TODO)
+        //   2.1 delete processed template and its sections in control files
         // 3. delete from meta
-        let mut meta = Meta::new(&group)?;
-        if ! meta.check(&file) {
-            return misc_error_file!("file is not in meta.txt", file.to_path_buf())
-        }
-        let link_file = if file.is_relative() {
-            self.home.join(file)
-        }
-        else {
-            return misc_error!("absolute paths are not supported yet")
+        let mut meta = Meta::new(group, &self.fs)?;
+        let entry = match meta.get(file) {
+            Some(entry) => entry.clone(),
+            None => return misc_error_file!("file is not in meta", file.to_path_buf()),
         };
+        let link_file = self.dest_path(&entry);
 
         if link_file.exists() {
             if ! self.fs.is_symlink(&link_file)? {
@@ -414,7 +864,7 @@ impl Confine {
             return Ok(())
         }
         
-        let src = group.abs_path().join(file);
+        let src = group.src_path(file);
         if ! src.exists() {
             debug!("{} already deleted, ok", src.display());
         }
@@ -422,10 +872,208 @@ impl Confine {
             self.fs.unlink(&src)?
         }
 
-        if meta.check(&file) {
-            meta.delete(&file)?
+        self.delete_processed(group, &entry)?;
+
+        if meta.check(file) {
+            meta.delete(file)?
+        }
+
+        Ok(())
+    }
+
+    fn delete_processed(&mut self, group: &Group, entry: &Entry) -> Result<()> {
+        // processed template or decrypted file
+        let template_name = group.template_name(&entry.path);
+        if entry.template || self.templates.needs_template(&template_name)? {
+            self.delete_template(&template_name)?;
+        }
+        else if entry.encrypted {
+            let decrypted = self.templates.processed_path(&template_name);
+            if fs::symlink_metadata(&decrypted).is_ok() {
+                self.fs.unlink_private(&decrypted)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_template(&mut self, template_name: &PathBuf) -> Result<()> {
+        let processed_file = self.templates.processed_path(template_name);
+        if let Ok(meta) = fs::symlink_metadata(&processed_file) {
+            // processed file with secrets is readable only by owner, its plaintext is not kept
+            if meta.permissions().mode() & 0o077 == 0 {
+                self.fs.unlink_private(&processed_file)?;
+            }
+            else {
+                self.fs.unlink(&processed_file)?;
+            }
+        }
+        if ! self.dry {
+            self.templates.forget(template_name)?;
+        }
+        for control_file in self.templates.control_files_for(template_name)? {
+            let question = format!("remove [\"{}\"] from {}?", template_name.display(), control_file.display());
+            if ! self.confirm(&question)? {
+                continue;
+            }
+            let content = templates::remove_section(&control_file, template_name)?;
+            self.fs.write(&control_file, &content)?;
+        }
+        Ok(())
+    }
+
+    fn confirm(&self, question: &str) -> Result<bool> {
+        if self.yes {
+            return Ok(true);
+        }
+        match self.ask(&format!("{} [y/N]", question))? {
+            Some(answer) => Ok(["y", "yes"].contains(&answer.as_str())),
+            None => {
+                warn!("{} no, not asking without terminal, use --yes", question);
+                Ok(false)
+            }
+        }
+    }
+
+    fn ask(&self, question: &str) -> Result<Option<String>> {
+        // lowercase answer, None if there is no terminal to ask
+        // safe: isatty only checks the descriptor
+        if unsafe { libc::isatty(0) } == 0 {
+            return Ok(None);
+        }
+        print!("{} ", question);
+        io::stdout().flush().context(IoError {path: PathBuf::from("<stdout>")})?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).context(IoError {path: PathBuf::from("<stdin>")})?;
+        Ok(Some(answer.trim().to_lowercase()))
+    }
+
+    fn dest_path(&self, entry: &Entry) -> PathBuf {
+        // where the link for meta entry lives: relative to home, or absolute
+        let path = entry.target.as_ref().unwrap_or(&entry.path);
+        if path.is_relative() {
+            self.home.join(path)
+        }
+        else {
+            path.clone()
+        }
+    }
+
+    fn list_backups(&self, host: Option<&str>) -> Result<()> {
+        let hosts = match host {
+            Some(host) => vec![host.to_string()],
+            None => self.backups.hosts()?,
+        };
+        for host in hosts {
+            println!("{}:", host);
+            for (file, generations) in self.backups.list(&host)? {
+                println!("  {}", file.display());
+                for item in generations {
+                    println!("    {}", item.generation);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn journal_dir(&self) -> PathBuf {
+        self.root.join("journal")
+    }
+
+    fn history(&self, verbose: bool) -> Result<()> {
+        for record in Record::list(&self.journal_dir())? {
+            let rolled_back = if record.rolled_back {
+                " (rolled back)"
+            }
+            else {
+                ""
+            };
+            println!("{:>4}  {}  {}  {}{}", record.id, record.timestamp, record.host, record.command, rolled_back);
+            if verbose {
+                for action in record.actions.iter() {
+                    println!("        {:?}", action);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&self, id: u64) -> Result<()> {
+        let dir = self.journal_dir();
+        let mut record = Record::load(&dir, id)?;
+        if record.rolled_back {
+            misc_error!(format!("{} is already rolled back", id))
+        }
+        let hostname = hostname();
+        if record.host != hostname {
+            warn!("{} was done on host {}, this is {}", id, record.host, hostname);
+        }
+        debug!("rollback {}: {}", id, record.command);
+        for action in record.actions.iter().rev() {
+            action.revert(&self.fs)?;
+        }
+        if ! self.dry {
+            record.rolled_back = true;
+            record.save(&dir)?;
+        }
+        Ok(())
+    }
+
+    fn template_vars(&mut self, group: &Group, file: &Path) -> Result<()> {
+        let control = match self.template.clone() {
+            Some(control) => control,
+            None => misc_error_file!("Template required for file", file.to_path_buf()),
+        };
+        let vars = self.templates.variables(&group.template_name(file), &control)?;
+        for (name, var) in vars {
+            if var.secret {
+                println!("{} = \"***\"  # {}, secret", name, var.source);
+            }
+            else {
+                println!("{} = {}  # {}", name, var.value, var.source);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_templates(&mut self) -> Result<()> {
+        // every file in meta of every group, and whether it is marked as template
+        let mut files = Vec::new();
+        for group in self.all_groups()? {
+            let meta = Meta::new(&group, &self.fs)?;
+            for file in meta.list()? {
+                let entry = meta.get(&PathBuf::from(file)).unwrap();
+                files.push((group.template_name(&entry.path), entry.template));
+            }
+        }
+        let problems = self.templates.check(&files)?;
+        for problem in problems.iter() {
+            println!("{}", problem);
         }
+        if ! problems.is_empty() {
+            misc_error!(format!("{} problem(s) found", problems.len()))
+        }
+        Ok(())
+    }
 
+    fn render_template(&mut self, group: &Group, file: &Path, diff: bool) -> Result<()> {
+        let control = match self.template.clone() {
+            Some(control) => control,
+            None => misc_error_file!("Template required for file", file.to_path_buf()),
+        };
+        let template_name = group.template_name(file);
+        let rendered = self.templates.render(&template_name, &group.src_path(file), &control)?;
+        if ! diff {
+            print!("{}", rendered);
+            return Ok(());
+        }
+        let processed_file = self.templates.processed_path(&template_name);
+        let processed = if processed_file.exists() {
+            fs::read_to_string(&processed_file).context(IoError {path: processed_file.clone()})?
+        }
+        else {
+            String::new()
+        };
+        print!("{}", unified_diff(&processed, &rendered, &processed_file.display().to_string(), &format!("{} ({})", template_name.display(), control)));
         Ok(())
     }
 
@@ -441,6 +1089,17 @@ impl Confine {
         }
     }
 
+    fn groups_from_args(&mut self, matches: &ArgMatches) -> Result<Vec<(Group, Vec<PathBuf>)>> {
+        // group and files if given, otherwise all groups with all their files
+        if matches.value_of("group").is_some() {
+            let (files, group) = self.get_files_from_args(matches)?;
+            Ok(vec![(group, files)])
+        }
+        else {
+            Ok(self.all_groups()?.into_iter().map(|g| (g, Vec::new())).collect())
+        }
+    }
+
     fn get_files_from_args(&mut self, matches: &ArgMatches) -> Result<(Vec<PathBuf>, Group)> {
         let files = match matches.values_of("files") {
             Some(files) => files.map(|f| f.to_string()).collect(),
@@ -461,7 +1120,7 @@ impl Confine {
         }
         else {
             let root = self.root.clone();
-            groups.insert(Group::new(self.dry, root, group_param).unwrap());
+            groups.insert(Group::new(root, group_param)?);
         }
 
         // check if any file is actually a group/file
@@ -487,6 +1146,14 @@ impl Confine {
         Ok((new_files, group))
     }
 
+    fn get_group_file(&mut self, p: &str) -> Result<(Group, PathBuf)> {
+        // group/file argument
+        match self.get_group_from_file(p) {
+            (Some(group), Some(file)) => Ok((group, file)),
+            _ => misc_error!(format!("expected group/file: {}", p)),
+        }
+    }
+
     fn get_group_from_file(&mut self, p: &str) -> (Option<Group>, Option<PathBuf>) {
         if let Some(idx) = p.find('/') {
             let dir = &p[0..idx];
@@ -503,6 +1170,137 @@ impl Confine {
         (None, Some(PathBuf::from(p)))
     }
 
+    fn existing_group(&mut self, name: &str) -> Result<Group> {
+        match self.find_group(name) {
+            Some(group) if Meta::exists_in(&group.abs_path()) => Ok(group),
+            _ => misc_error_file!("group not found", self.root.join(name)),
+        }
+    }
+
+    fn new_group(&mut self, name: &str) -> Result<()> {
+        let group = Group::new(self.root.clone(), name)?;
+        let dir = group.abs_path();
+        if fs::symlink_metadata(&dir).is_ok() {
+            misc_error_file!("group already exists", dir.clone())
+        }
+        self.fs.mkpath(&dir)?;
+        Meta::new(&group, &self.fs)?.save()
+    }
+
+    fn list_groups(&mut self) -> Result<()> {
+        // files of each group and how many of those for this host are in sync
+        for group in self.all_groups()? {
+            let total = Meta::new(&group, &self.fs)?.list()?.len();
+            let (mut linked, mut out_of_sync) = (0, 0);
+            for entry in self.host_entries(&group, Vec::new())? {
+                match self.file_status(&group, &entry)? {
+                    LinkStatus::Linked | LinkStatus::Copied => linked += 1,
+                    _ => out_of_sync += 1,
+                }
+            }
+            println!("{:<18} {} file(s), {} linked, {} out of sync", group.to_string(), total, linked, out_of_sync);
+        }
+        Ok(())
+    }
+
+    fn group_link(&mut self, group: &Group, entry: &Entry) -> Result<Option<bool>> {
+        // is home file a link to group file, or to processed one (true)
+        let dest = self.dest_path(entry);
+        if ! fs::symlink_metadata(&dest).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Ok(None);
+        }
+        let target = match dest.canonicalize() {
+            Ok(target) => target,
+            Err(_) => return Ok(None),
+        };
+        let points_to = |p: &PathBuf| p.canonicalize().is_ok_and(|p| p == target);
+        if points_to(&group.src_path(&entry.path)) {
+            Ok(Some(false))
+        }
+        else if points_to(&self.templates.processed_path(&group.template_name(&entry.path))) {
+            Ok(Some(true))
+        }
+        else {
+            Ok(None)
+        }
+    }
+
+    fn rename_group(&mut self, name: &str, new_name: &str) -> Result<()> {
+        // move group dir, its processed files and sections in control files, re-point links in home
+        let group = self.existing_group(name)?;
+        let new_group = Group::new(self.root.clone(), new_name)?;
+        if fs::symlink_metadata(new_group.abs_path()).is_ok() {
+            misc_error_file!("group already exists", new_group.abs_path())
+        }
+        // meta.txt is migrated here, before group dir is moved
+        let mut links = Vec::new();
+        for entry in self.host_entries(&group, Vec::new())? {
+            let src = match self.group_link(&group, &entry)? {
+                Some(true) => self.templates.processed_path(&new_group.template_name(&entry.path)),
+                Some(false) => new_group.src_path(&entry.path),
+                None => continue,
+            };
+            links.push((self.dest_path(&entry), src));
+        }
+
+        self.fs.rename(&group.abs_path(), &new_group.abs_path())?;
+        for dir in self.templates.processed_group_dirs(&group.dir)? {
+            let new_dir = dir.with_file_name(&new_group.dir);
+            self.fs.rename(&dir, &new_dir)?;
+        }
+        for control_file in self.templates.control_files()? {
+            if let Some(content) = templates::rename_sections(&control_file, &group.dir, &new_group.dir)? {
+                self.fs.write(&control_file, &content)?;
+            }
+        }
+        if ! self.dry {
+            self.templates.rename_group(&group.dir, &new_group.dir)?;
+            // GROUP variable is different now, and control files changed anyway
+            for template_name in self.templates.refresh(Some(&new_group.dir))? {
+                debug!("refreshed {}", template_name.display());
+            }
+        }
+        for (dest, src) in links {
+            self.fs.unlink(&dest)?;
+            self.fs.symlink(&src, &dest)?;
+        }
+        self.groups.remove(name);
+        Ok(())
+    }
+
+    fn remove_group(&mut self, name: &str) -> Result<()> {
+        // undo links to group files, then remove group with its processed files
+        let group = self.existing_group(name)?;
+        let meta = Meta::new(&group, &self.fs)?;
+        let files = meta.list()?;
+        if ! self.confirm(&format!("remove group {} with {} file(s)?", group, files.len()))? {
+            return Ok(());
+        }
+        for entry in self.host_entries(&group, Vec::new())? {
+            if self.group_link(&group, &entry)?.is_some() {
+                self.undo_link_file(&group, &entry)?;
+            }
+        }
+        for file in files {
+            let entry = meta.get(&PathBuf::from(file)).unwrap().clone();
+            self.delete_processed(&group, &entry)?;
+        }
+        self.fs.unlink(&group.abs_path())?;
+        self.groups.remove(name);
+        Ok(())
+    }
+
+    fn all_groups(&mut self) -> Result<Vec<Group>> {
+        // every dir in root that has meta
+        let mut names = self.root.read_dir().context(IoError {path: self.root.clone()})?
+            .filter_map(|e| e.ok())
+            .filter(|e| Meta::exists_in(&e.path()))
+            .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names.iter().filter_map(|g| self.find_group(g)).collect())
+    }
+
     fn find_group(&mut self, g: &str) -> Option<Group> {
         if g.is_empty() {
             return None;
@@ -512,9 +1310,9 @@ impl Confine {
         }
         let p = self.root.join(g);
         if p.is_dir() {
-            // not checking if meta.txt presents in dir, thou it seems like a good idea, because on
+            // not checking if meta presents in dir, thou it seems like a good idea, because on
             // first mv there'll be no such file
-            let group = Group::new(self.dry, self.root.clone(), g).unwrap();
+            let group = Group::new(self.root.clone(), g).ok()?;
             self.groups.insert(g.to_string(), group.clone());
             return Some(group);
         }
@@ -522,3 +1320,82 @@ impl Confine {
     }
 
 }
+
+pub fn hostname() -> String {
+    hostname::get_hostname().unwrap()
+}
+
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    // empty if there is no difference
+    similar::TextDiff::from_lines(old, new).unified_diff().header(old_name, new_name).to_string()
+}
+
+fn print_diff(old: Option<Vec<u8>>, new: Option<Vec<u8>>, old_name: &str, new_name: &str) -> bool {
+    // missing file is /dev/null, like in diff -N; true if there is a difference
+    if old == new {
+        return false;
+    }
+    let old_name = if old.is_some() { old_name } else { "/dev/null" };
+    let new_name = if new.is_some() { new_name } else { "/dev/null" };
+    let (old, new) = (old.unwrap_or_default(), new.unwrap_or_default());
+    match (std::str::from_utf8(&old), std::str::from_utf8(&new)) {
+        (Ok(old), Ok(new)) => print!("{}", unified_diff(old, new, old_name, new_name)),
+        _ => println!("Binary files {} and {} differ", old_name, new_name),
+    }
+    true
+}
+
+fn walk_files(dir: &Path, base: &Path, files: &mut BTreeSet<PathBuf>) -> Result<()> {
+    // paths of files under dir, relative to base
+    if ! dir.is_dir() {
+        return Ok(());
+    }
+    for entry in dir.read_dir().context(IoError {path: dir})? {
+        let path = entry.context(IoError {path: dir})?.path();
+        if path.is_dir() {
+            walk_files(&path, base, files)?;
+        }
+        else {
+            files.insert(path.strip_prefix(base).unwrap().to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    // files, or dirs with the same files
+    if a.is_dir() && b.is_dir() {
+        let (mut a_files, mut b_files) = (BTreeSet::new(), BTreeSet::new());
+        walk_files(a, a, &mut a_files)?;
+        walk_files(b, b, &mut b_files)?;
+        if a_files != b_files {
+            return Ok(false);
+        }
+        for file in a_files {
+            if ! same_content(&a.join(&file), &b.join(&file))? {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+    if ! a.is_file() || ! b.is_file() {
+        return Ok(false);
+    }
+    let a_content = fs::read(a).context(IoError {path: a})?;
+    let b_content = fs::read(b).context(IoError {path: b})?;
+    Ok(a_content == b_content)
+}
+
+pub fn username() -> String {
+    // $USER is not set (or empty) in cron, systemd units and containers
+    let from_env = std::env::var("USER").ok().filter(|u| ! u.is_empty())
+        .or_else(|| std::env::var("LOGNAME").ok().filter(|u| ! u.is_empty()));
+    if let Some(user) = from_env {
+        return user;
+    }
+    let pw = unsafe { libc::getpwuid(libc::getuid()) };
+    if pw.is_null() {
+        return String::new();
+    }
+    unsafe { std::ffi::CStr::from_ptr((*pw).pw_name) }.to_string_lossy().to_string()
+}
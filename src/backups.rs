@@ -0,0 +1,209 @@
+extern crate chrono;
+
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::fs;
+
+use file_utils::FileUtils;
+use app::hostname;
+use crypt::{self, Crypt};
+
+use snafu::*;
+use errors::*;
+
+/*
+files overwritten by link are saved to backup/{hostname}/, which mirrors home dir
+for files in home, and / for files with absolute path.
+each backup is a new generation, named after the time it was made:
+backup/{hostname}/.bashrc~20200121-101201
+files in place of encrypted entries are backed up encrypted, and decrypted on restore.
+*/
+
+const DEFAULT_KEEP: usize = 5;
+
+#[derive(Debug)]
+pub struct BackupItem {
+    pub rel_path: PathBuf,
+    pub generation: String,
+    path: PathBuf,
+}
+
+pub struct Backups {
+    root: PathBuf,
+    home: PathBuf,
+    fs: FileUtils,
+    keep: Option<usize>,
+}
+
+impl Backups {
+    pub fn new(root: PathBuf, home: PathBuf, fs: FileUtils, keep: Option<usize>) -> Self {
+        Self {root, home, fs, keep}
+    }
+
+    fn host_dir(&self, host: &str) -> PathBuf {
+        self.root.join("backup").join(host)
+    }
+
+    fn rel_path(&self, path: &Path) -> Result<PathBuf> {
+        let prefix = if path.starts_with(&self.home) {
+            self.home.clone()
+        }
+        else {
+            PathBuf::from("/")
+        };
+        Ok(path.strip_prefix(&prefix).context(StripPrefixError {path, prefix: prefix.clone() })?.to_owned())
+    }
+
+    pub fn backup(&self, path: &PathBuf, crypt: Option<&Crypt>) -> Result<()> {
+        let path = path.canonicalize().context(IoError {path})?;
+        let rel_path = self.rel_path(&path)?;
+        let host = hostname();
+        let base = self.host_dir(&host).join(&rel_path);
+        let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+        let mut backup_dest = generation_path(&base, &stamp);
+        let mut n = 0;
+        while fs::symlink_metadata(&backup_dest).is_ok() {
+            n += 1;
+            backup_dest = generation_path(&base, &format!("{}-{}", stamp, n));
+        }
+
+        trace!("backup {:?} to {:?}", path, backup_dest);
+
+        match crypt {
+            Some(crypt) => {
+                let content = fs::read(&path).context(IoError {path: path.clone()})?;
+                let armored = crypt.encrypt(&content)?;
+                self.fs.mkpath(&backup_dest.parent().unwrap().to_owned())?;
+                self.fs.write(&backup_dest, armored)?;
+            }
+            None => self.fs.copy_to(&path, &backup_dest)?,
+        }
+
+        if let Some(keep) = self.keep {
+            self.prune(&host, keep, Some(&rel_path))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn hosts(&self) -> Result<Vec<String>> {
+        let dir = self.root.join("backup");
+        if ! dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut hosts = dir.read_dir().context(IoError {path: dir.clone()})?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+            .collect::<Vec<_>>();
+        hosts.sort();
+        Ok(hosts)
+    }
+
+    pub fn list(&self, host: &str) -> Result<BTreeMap<PathBuf, Vec<BackupItem>>> {
+        // backed up files, relative to host dir => generations, newest first
+        let host_dir = self.host_dir(host);
+        let mut items = Vec::new();
+        if host_dir.is_dir() {
+            walk(&host_dir, &host_dir, &mut items)?;
+        }
+        let mut files = BTreeMap::new();
+        for item in items {
+            files.entry(item.rel_path.clone()).or_insert_with(Vec::new).push(item);
+        }
+        for generations in files.values_mut() {
+            generations.sort_by(|a, b| b.generation.cmp(&a.generation));
+        }
+        Ok(files)
+    }
+
+    pub fn restore(&self, path: &PathBuf, host: &str, generation: Option<&str>, crypt: &Crypt) -> Result<()> {
+        // put backup of path back in place of symlink
+        let path = if path.is_relative() {
+            self.home.join(path)
+        }
+        else {
+            path.clone()
+        };
+        let rel_path = self.rel_path(&path)?;
+        let mut files = self.list(host)?;
+        let generations = files.remove(&rel_path).unwrap_or_default();
+        let item = match generation {
+            Some(generation) => generations.into_iter().find(|i| i.generation == generation),
+            None => generations.into_iter().next(),
+        };
+        let src = match item {
+            Some(item) => item.path,
+            None => misc_error_file!(format!("no backup for host {}", host), path.clone()),
+        };
+        if fs::symlink_metadata(&path).is_ok() {
+            if ! self.fs.is_symlink(&path)? {
+                misc_error_file!("file exists and is not a symlink, not overwriting", path.clone())
+            }
+            self.fs.unlink(&path)?;
+        }
+        if crypt::is_encrypted(&src) {
+            let content = crypt.decrypt(&src)?;
+            self.fs.write(&path, content)?;
+            return self.fs.chmod(&path, 0o600);
+        }
+        self.fs.copy_to(&src, &path)
+    }
+
+    pub fn default_keep(&self) -> usize {
+        self.keep.unwrap_or(DEFAULT_KEEP)
+    }
+
+    pub fn prune(&self, host: &str, keep: usize, rel_path: Option<&PathBuf>) -> Result<()> {
+        // remove all but `keep` newest generations of every file (or just of rel_path)
+        for (file, generations) in self.list(host)? {
+            if rel_path.is_some_and(|p| *p != file) {
+                continue;
+            }
+            for item in generations.iter().skip(keep) {
+                debug!("prune backup {} {}", file.display(), item.generation);
+                self.fs.unlink(&item.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn generation_path(base: &Path, generation: &str) -> PathBuf {
+    let name = format!("{}~{}", base.file_name().unwrap().to_string_lossy(), generation);
+    base.with_file_name(name)
+}
+
+fn split_generation(name: &str) -> Option<(&str, &str)> {
+    // .bashrc~20200121-101201 => (.bashrc, 20200121-101201)
+    let idx = name.rfind('~')?;
+    let (base, generation) = (&name[..idx], &name[(idx+1)..]);
+    let stamp = generation.as_bytes();
+    if stamp.len() < 15 || ! stamp[..15].iter().enumerate().all(|(i, c)| if i == 8 { *c == b'-' } else { c.is_ascii_digit() }) {
+        return None;
+    }
+    Some((base, generation))
+}
+
+fn walk(dir: &Path, base: &Path, items: &mut Vec<BackupItem>) -> Result<()> {
+    for entry in dir.read_dir().context(IoError {path: dir})? {
+        let path = entry.context(IoError {path: dir})?.path();
+        let meta = fs::symlink_metadata(&path).context(IoError {path: path.clone()})?;
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if let Some((file_name, generation)) = split_generation(&name) {
+            let rel_path = path.with_file_name(file_name).strip_prefix(base).unwrap().to_path_buf();
+            items.push(BackupItem {rel_path, generation: generation.to_string(), path});
+        }
+        else if meta.is_dir() {
+            walk(&path, base, items)?;
+        }
+        else {
+            // made before backups were versioned
+            let mtime = meta.modified().context(IoError {path: path.clone()})?;
+            let generation = chrono::DateTime::<chrono::Local>::from(mtime).format("%Y%m%d-%H%M%S").to_string();
+            let rel_path = path.strip_prefix(base).unwrap().to_path_buf();
+            items.push(BackupItem {rel_path, generation, path});
+        }
+    }
+    Ok(())
+}
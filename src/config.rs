@@ -0,0 +1,127 @@
+extern crate toml;
+
+use std::path::Path;
+use std::fs;
+
+use snafu::*;
+use errors::*;
+
+/*
+storage-wide settings, tune/confine.toml:
+
+[backup]
+keep = 5
+
+# control file for link when -t is not given, first matching rule wins
+[[templates.select]]
+host = "work-*"
+user = "nikita"
+os = "linux"
+control = "work"
+
+[templates]
+# where { secret = "name" } values are looked up, default is ~/.config/confine/secrets.toml
+secrets = "~/.secrets/confine.toml"
+# where processed templates are kept, default is tune/templates/processed in the storage
+processed = "~/.local/state/confine/processed"
+
+# template variables for all control files
+[templates.vars]
+GIT_USER = "Nikita Bilous"
+
+# key for encrypted files, default is ~/.config/confine/key
+[encryption]
+key = "~/.secrets/confine.key"
+*/
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BackupConfig {
+    // generations of each file to keep, all if not set
+    pub keep: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TemplateRule {
+    // glob patterns, rule matches if all that are set match
+    pub host: Option<String>,
+    pub user: Option<String>,
+    pub os: Option<String>,
+    pub control: String,
+}
+
+impl TemplateRule {
+    pub fn matches(&self, host: &str, user: &str, os: &str) -> bool {
+        let check = |pattern: &Option<String>, value: &str| pattern.as_ref().is_none_or(|p| glob_match(p, value));
+        check(&self.host, host) && check(&self.user, user) && check(&self.os, os)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TemplatesConfig {
+    #[serde(default)]
+    pub select: Vec<TemplateRule>,
+    #[serde(default)]
+    pub vars: toml::value::Table,
+    // file with secrets for templates, relative to home
+    pub secrets: Option<String>,
+    // dir for processed templates, relative to home
+    pub processed: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EncryptionConfig {
+    // key file, relative to home
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub templates: TemplatesConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+}
+
+impl Config {
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join("tune/confine.toml");
+        if ! path.exists() {
+            return Ok(Self::default());
+        }
+        trace!("load config {:?}", path);
+        let content = fs::read_to_string(&path).context(IoError {path: path.clone()})?;
+        match toml::from_str(&content) {
+            Ok(config) => Ok(config),
+            Err(e) => misc_error_file!(e, path),
+        }
+    }
+}
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    // shell-like: * is any string, ? is any char
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        }
+        else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        }
+        else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        }
+        else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
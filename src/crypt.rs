@@ -0,0 +1,138 @@
+extern crate chacha20poly1305;
+extern crate getrandom;
+
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use self::chacha20poly1305::{ChaCha20Poly1305, Nonce, KeyInit};
+use self::chacha20poly1305::aead::Aead;
+
+use snafu::*;
+use errors::*;
+
+/*
+encrypted files in groups, see `move --encrypt`.
+key is 32 random bytes, hex encoded, in a local key file (default ~/.config/confine/key),
+which is created on first use and never leaves the machine. copy it to other machines by hand.
+the key file is not journaled, so rollback never removes it; dry run uses a throwaway key.
+
+encrypted file is ChaCha20-Poly1305 nonce and ciphertext, hex encoded:
+
+-----BEGIN CONFINE ENCRYPTED FILE-----
+<hex, 64 chars per line>
+-----END CONFINE ENCRYPTED FILE-----
+*/
+
+const DEFAULT_KEY_FILE: &str = ".config/confine/key";
+const BEGIN: &str = "-----BEGIN CONFINE ENCRYPTED FILE-----";
+const END: &str = "-----END CONFINE ENCRYPTED FILE-----";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+pub struct Crypt {
+    key_file: PathBuf,
+    dry: bool,
+}
+
+impl Crypt {
+    pub fn new(home: &Path, key_file: Option<&String>, dry: bool) -> Self {
+        let key_file = match key_file {
+            Some(file) => home.join(file.strip_prefix("~/").unwrap_or(file)),
+            None => home.join(DEFAULT_KEY_FILE),
+        };
+        Self {key_file, dry}
+    }
+
+    fn cipher(&self, create: bool) -> Result<ChaCha20Poly1305> {
+        let key = if ! self.key_file.exists() {
+            if ! create {
+                misc_error_file!("key file not found, copy it from machine where files were encrypted", self.key_file.clone())
+            }
+            self.create_key()?
+        }
+        else {
+            let content = fs::read_to_string(&self.key_file).context(IoError {path: self.key_file.clone()})?;
+            match from_hex(content.trim()) {
+                Some(ref key) if key.len() == KEY_LEN => key.clone(),
+                _ => misc_error_file!(format!("key must be {} hex encoded bytes", KEY_LEN), self.key_file.clone()),
+            }
+        };
+        Ok(ChaCha20Poly1305::new_from_slice(&key).unwrap())
+    }
+
+    fn create_key(&self) -> Result<Vec<u8>> {
+        let mut key = [0u8; KEY_LEN];
+        if let Err(e) = getrandom::getrandom(&mut key) {
+            misc_error!(format!("can not generate key: {}", e))
+        }
+        if self.dry {
+            warn!("dry: create key file {}", self.key_file.display());
+            return Ok(key.to_vec());
+        }
+        warn!("create key file {}", self.key_file.display());
+        let dir = self.key_file.parent().unwrap();
+        fs::create_dir_all(dir).context(IoError {path: dir})?;
+        let mut f = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&self.key_file)
+            .context(IoError {path: self.key_file.clone()})?;
+        writeln!(f, "{}", to_hex(&key)).context(IoError {path: self.key_file.clone()})?;
+        Ok(key.to_vec())
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<String> {
+        let cipher = self.cipher(true)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        if let Err(e) = getrandom::getrandom(&mut nonce) {
+            misc_error!(format!("can not generate nonce: {}", e))
+        }
+        let ciphertext = match cipher.encrypt(&Nonce::from(nonce), plaintext) {
+            Ok(c) => c,
+            Err(e) => misc_error!(format!("encryption failed: {}", e)),
+        };
+        let data = to_hex(&[&nonce[..], &ciphertext[..]].concat());
+        let mut armored = String::from(BEGIN) + "\n";
+        for line in data.as_bytes().chunks(64) {
+            armored += std::str::from_utf8(line).unwrap();
+            armored += "\n";
+        }
+        Ok(armored + END + "\n")
+    }
+
+    pub fn decrypt(&self, path: &PathBuf) -> Result<Vec<u8>> {
+        let content = fs::read_to_string(path).context(IoError {path: path.clone()})?;
+        let data = content.lines()
+            .map(|l| l.trim())
+            .skip_while(|l| *l != BEGIN).skip(1)
+            .take_while(|l| *l != END)
+            .collect::<String>();
+        let data = match from_hex(&data) {
+            Some(ref data) if data.len() > NONCE_LEN => data.clone(),
+            _ => misc_error_file!("not an encrypted file", path.clone()),
+        };
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[..NONCE_LEN]);
+        match self.cipher(false)?.decrypt(&Nonce::from(nonce), &data[NONCE_LEN..]) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => misc_error_file!(format!("can not decrypt, wrong key {}?", self.key_file.display()), path.clone()),
+        }
+    }
+}
+
+pub fn is_encrypted(path: &Path) -> bool {
+    match fs::read(path) {
+        Ok(content) => content.starts_with(BEGIN.as_bytes()),
+        Err(_) => false,
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if ! s.len().is_multiple_of(2) || ! s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i+2], 16).ok()).collect()
+}
@@ -22,8 +22,17 @@ pub enum Error {
     // #[snafu(display("File {}: template required", file.display()))]
     // TemplateRequred { file: PathBuf },
 
-    #[snafu(display("Template processing error: {}", template_name.display()))] // TODO add tera error as source
-    TemplateError { template_name: PathBuf, source: tera::Error },
+    #[snafu(display("Template processing error: {} (variables from {})", details, control))]
+    TemplateError {
+        template_name: PathBuf,
+        control: String,
+        details: String,
+        #[snafu(source(from(tera::Error, Box::new)))]
+        source: Box<tera::Error>,
+    },
+
+    #[snafu(display("Template processing error: {}: undefined variables {} (variables from {})", template_name.display(), names, control))]
+    TemplateUndefined { template_name: PathBuf, control: String, names: String },
 
     #[snafu(display("{}", message))]
     MiscError { message: String },
@@ -46,14 +55,14 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 #[macro_export]
 macro_rules! misc_error {
     ( $x:expr ) => {
-        Err(Error::MiscError {message: $x.to_string()})?
+        Err($crate::errors::Error::MiscError {message: $x.to_string()})?
     };
 }
 
 #[macro_export]
 macro_rules! misc_error_file {
     ( $x:expr, $file:expr ) => {
-        Err(Error::MiscErrorFile {message: $x.to_string(), file: $file})?
+        Err($crate::errors::Error::MiscErrorFile {message: $x.to_string(), file: $file})?
     };
 }
 
@@ -1,16 +1,49 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::io::Write;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use journal::{Journal, Action, content_hash};
+use templates::sha256;
 
 use snafu::*;
 use errors::*;
 
+// clones share the journal
+#[derive(Clone)]
 pub struct FileUtils {
     dry: bool,
+    journal: Rc<RefCell<Journal>>,
 }
 
 impl FileUtils {
     pub fn new(dry: bool) -> Self {
-        FileUtils {dry}
+        FileUtils {dry, journal: Rc::new(RefCell::new(Journal::default()))}
+    }
+
+    fn record(&self, action: Action) {
+        self.journal.borrow_mut().record(action);
+    }
+
+    fn stash_path(&self, p: &Path) -> PathBuf {
+        // removed file is kept next to original until commit
+        let name = format!(".{}.confine~{}~{}", p.file_name().unwrap().to_string_lossy(), std::process::id(), self.journal.borrow().len());
+        p.with_file_name(name)
+    }
+
+    pub fn persist(&self, dir: PathBuf, command: String, host: String) {
+        // save journal on commit
+        self.journal.borrow_mut().persist(dir, command, host);
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        self.journal.borrow_mut().commit()
+    }
+
+    pub fn rollback(&self) -> Result<()> {
+        self.journal.borrow_mut().rollback()
     }
 
     pub fn log<S: Into<String>>(&self, msg: S) {
@@ -28,11 +61,22 @@ impl FileUtils {
     }
 
     pub fn unlink(&self, p: &PathBuf) -> Result<()> {
+        self.remove(p, false)
+    }
+
+    pub fn unlink_private(&self, p: &PathBuf) -> Result<()> {
+        // like unlink, but removed file is not kept in saved journal, for plaintext of encrypted files
+        self.remove(p, true)
+    }
+
+    fn remove(&self, p: &PathBuf, private: bool) -> Result<()> {
         self.log(format!("rm {}", p.display()));
         if self.dry {
             return Ok(());
         }
-        fs_extra::remove_items(&vec![p]).context(FsError {path: p})?;
+        let stash = self.stash_path(p);
+        fs::rename(p, &stash).context(IoError {path: p})?;
+        self.record(Action::Remove {path: p.clone(), stash, private});
 
         Ok(())
     }
@@ -43,7 +87,12 @@ impl FileUtils {
             return Ok(());
         }
 
+        let mut created = p.ancestors().take_while(|a| ! a.exists()).map(|a| a.to_path_buf()).collect::<Vec<_>>();
+        created.reverse();
         fs::create_dir_all(p).context(IoError {path: p})?;
+        for path in created {
+            self.record(Action::Mkdir {path});
+        }
         Ok(())
     }
 
@@ -54,6 +103,7 @@ impl FileUtils {
         }
 
         std::os::unix::fs::symlink(&src, &dst).context(IoError {path: src})?;
+        self.record(Action::Symlink {src: src.clone(), dst: dst.clone()});
 
         Ok(())
     }
@@ -69,9 +119,115 @@ impl FileUtils {
             self.mkpath(&dst)?;
         }
 
-
+        let hash = Some(content_hash(src)?);
+        self.record(Action::Copy {src: src.clone(), dst: dst.join(src.file_name().unwrap()), hash});
         fs_extra::copy_items(&vec![src], &dst, &fs_extra::dir::CopyOptions::new()).context(FsError {path: src})?;
 
         Ok(())
     }
+
+    pub fn copy_to(&self, src: &PathBuf, dst: &PathBuf) -> Result<()> {
+        // unlike copy, dst is the path of the copy, not the dir to put it into
+        self.log(format!("copy {} -> {}", src.display(), dst.display()));
+        if self.dry {
+            return Ok(());
+        }
+
+        let dst_parent = dst.parent().unwrap().to_owned();
+        if ! dst_parent.exists() {
+            self.mkpath(&dst_parent)?;
+        }
+
+        let hash = Some(content_hash(src)?);
+        self.record(Action::Copy {src: src.clone(), dst: dst.clone(), hash});
+        if src.is_dir() {
+            fs::create_dir_all(dst).context(IoError {path: dst.clone()})?;
+            let mut options = fs_extra::dir::CopyOptions::new();
+            options.content_only = true;
+            fs_extra::dir::copy(src, dst, &options).context(FsError {path: src.clone()})?;
+        }
+        else {
+            fs::copy(src, dst).context(IoError {path: src.clone()})?;
+        }
+
+        Ok(())
+    }
+
+    pub fn rename(&self, src: &PathBuf, dst: &PathBuf) -> Result<()> {
+        self.log(format!("mv {} {}", src.display(), dst.display()));
+        if self.dry {
+            return Ok(());
+        }
+        if fs::symlink_metadata(dst).is_ok() {
+            misc_error_file!("file exists", dst.clone())
+        }
+        fs::rename(src, dst).context(IoError {path: src.clone()})?;
+        self.record(Action::Rename {src: src.clone(), dst: dst.clone()});
+
+        Ok(())
+    }
+
+    pub fn chmod(&self, p: &PathBuf, mode: u32) -> Result<()> {
+        self.log(format!("chmod {:o} {}", mode, p.display()));
+        if self.dry {
+            return Ok(());
+        }
+
+        let old_mode = fs::metadata(p).context(IoError {path: p.clone()})?.permissions().mode() & 0o7777;
+        fs::set_permissions(p, fs::Permissions::from_mode(mode)).context(IoError {path: p.clone()})?;
+        self.record(Action::Chmod {path: p.clone(), mode, old_mode});
+
+        Ok(())
+    }
+
+    pub fn write<C: AsRef<[u8]>>(&self, p: &PathBuf, content: C) -> Result<()> {
+        self.write_file(p, content.as_ref(), false)
+    }
+
+    pub fn write_private<C: AsRef<[u8]>>(&self, p: &PathBuf, content: C) -> Result<()> {
+        // readable only by owner, old content is not kept in saved journal
+        self.write_file(p, content.as_ref(), true)
+    }
+
+    fn write_file(&self, p: &PathBuf, content: &[u8], private: bool) -> Result<()> {
+        self.log(format!("write {}", p.display()));
+        if self.dry {
+            return Ok(());
+        }
+
+        let stash = if fs::symlink_metadata(p).is_ok() {
+            let stash = self.stash_path(p);
+            fs::rename(p, &stash).context(IoError {path: p.clone()})?;
+            Some(stash)
+        }
+        else {
+            None
+        };
+        self.record(Action::Write {path: p.clone(), stash, hash: Some(sha256(content)), private});
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        if private {
+            options.mode(0o600);
+        }
+        let mut f = options.open(p).context(IoError {path: p.clone()})?;
+        f.write_all(content).context(IoError {path: p.clone()})?;
+
+        Ok(())
+    }
+
+    pub fn restore(&self, src: &PathBuf, dst: &PathBuf) -> Result<()> {
+        // bring back file kept in journal
+        self.log(format!("restore {} -> {}", src.display(), dst.display()));
+        if self.is_symlink(src)? {
+            let dst_parent = dst.parent().unwrap().to_owned();
+            if ! dst_parent.exists() {
+                self.mkpath(&dst_parent)?;
+            }
+            let target = fs::read_link(src).context(IoError {path: src.clone()})?;
+            self.symlink(&target, dst)
+        }
+        else {
+            self.copy_to(src, dst)
+        }
+    }
 }
@@ -0,0 +1,111 @@
+extern crate tera;
+
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use self::tera::{Tera, Value};
+
+/*
+tera functions and filters for templates, in addition to tera built-ins:
+
+{% if command_exists(name="rg") %}...{% endif %}
+{% if file_exists(path="~/.cargo/env") %}...{% endif %}
+{{ read_file(path=".config/ripgrep/ignore") }}
+{{ env(name="EDITOR", default="vi") }}
+{{ path_join(parts=[HOME, ".local", "bin"]) }}
+alias ll={{ "ls -l --color" | shell_quote }}
+
+relative paths and ~ are resolved against home dir
+*/
+
+type Args = HashMap<String, Value>;
+
+pub fn register(tera: &mut Tera, home: &Path) {
+    let home = home.to_path_buf();
+    tera.register_function("command_exists", command_exists);
+    let h = home.clone();
+    tera.register_function("file_exists", move |args: &Args| file_exists(args, &h));
+    tera.register_function("read_file", move |args: &Args| read_file(args, &home));
+    tera.register_function("env", env);
+    tera.register_function("path_join", path_join);
+    tera.register_filter("shell_quote", shell_quote);
+}
+
+fn arg<'a>(args: &'a Args, function: &str, name: &str) -> tera::Result<&'a str> {
+    match args.get(name) {
+        Some(Value::String(s)) => Ok(s),
+        Some(v) => Err(format!("{}: `{}` must be a string, got {}", function, name, v).into()),
+        None => Err(format!("{}: `{}` is required", function, name).into()),
+    }
+}
+
+fn expand(path: &str, home: &Path) -> PathBuf {
+    if path == "~" {
+        home.to_path_buf()
+    }
+    else if let Some(rest) = path.strip_prefix("~/") {
+        home.join(rest)
+    }
+    else {
+        home.join(path) // join keeps absolute path as is
+    }
+}
+
+fn command_exists(args: &Args) -> tera::Result<Value> {
+    let name = arg(args, "command_exists", "name")?;
+    let is_executable = |p: &Path| fs::metadata(p).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+    let found = if name.contains('/') {
+        is_executable(Path::new(name))
+    }
+    else {
+        std::env::var_os("PATH").is_some_and(|paths| std::env::split_paths(&paths).any(|dir| is_executable(&dir.join(name))))
+    };
+    Ok(Value::Bool(found))
+}
+
+fn file_exists(args: &Args, home: &Path) -> tera::Result<Value> {
+    let path = expand(arg(args, "file_exists", "path")?, home);
+    Ok(Value::Bool(path.exists()))
+}
+
+fn read_file(args: &Args, home: &Path) -> tera::Result<Value> {
+    let path = expand(arg(args, "read_file", "path")?, home);
+    match fs::read_to_string(&path) {
+        Ok(content) => Ok(Value::String(content)),
+        Err(e) => Err(format!("read_file: {}: {}", path.display(), e).into()),
+    }
+}
+
+fn env(args: &Args) -> tera::Result<Value> {
+    let name = arg(args, "env", "name")?;
+    match (std::env::var(name), args.get("default")) {
+        (Ok(value), _) => Ok(Value::String(value)),
+        (Err(_), Some(default)) => Ok(default.clone()),
+        (Err(_), None) => Err(format!("env: {} is not set and no default given", name).into()),
+    }
+}
+
+fn path_join(args: &Args) -> tera::Result<Value> {
+    let parts = match args.get("parts") {
+        Some(Value::Array(parts)) => parts,
+        _ => return Err("path_join: `parts` must be an array".into()),
+    };
+    let mut path = PathBuf::new();
+    for part in parts {
+        match part {
+            Value::String(s) => path.push(s),
+            v => return Err(format!("path_join: parts must be strings, got {}", v).into()),
+        }
+    }
+    Ok(Value::String(path.display().to_string()))
+}
+
+fn shell_quote(value: &Value, _: &Args) -> tera::Result<Value> {
+    let s = match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    Ok(Value::String(format!("'{}'", s.replace('\'', "'\\''"))))
+}
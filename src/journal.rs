@@ -0,0 +1,429 @@
+extern crate toml;
+extern crate chrono;
+
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use file_utils::FileUtils;
+use templates::sha256;
+
+use snafu::*;
+use errors::*;
+
+/*
+filesystem actions done by FileUtils, in order to undo them if something goes wrong.
+removed and overwritten files are not deleted right away, but renamed to "stash"
+next to the original, and deleted on commit.
+
+for commands that change something (move, link, undo, delete) journal is saved on commit to
+journal/<id>.toml, and stashed files are kept in journal/<id>/, so the run can be reverted later.
+private stashes (plaintext of encrypted files, processed secrets) are never kept, such removals
+and writes can't be reverted.
+written and copied files keep sha256 of their content, revert refuses to touch them if they changed since
+*/
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action {
+    Mkdir { path: PathBuf },
+    Copy {
+        src: PathBuf,
+        dst: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<String>,
+    },
+    Remove {
+        path: PathBuf,
+        stash: PathBuf,
+        #[serde(default, skip_serializing_if = "is_false")]
+        private: bool,
+    },
+    Symlink { src: PathBuf, dst: PathBuf },
+    Rename { src: PathBuf, dst: PathBuf },
+    Write {
+        path: PathBuf,
+        stash: Option<PathBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<String>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        private: bool,
+    },
+    Chmod { path: PathBuf, mode: u32, old_mode: u32 },
+}
+
+impl Action {
+    pub fn undo(&self) -> Result<()> {
+        match self {
+            Action::Mkdir { path } => {
+                warn!("rollback: rmdir {}", path.display());
+                if path.is_dir() {
+                    fs::remove_dir(path).context(IoError {path: path.clone()})?;
+                }
+            }
+            Action::Copy { dst, .. } => {
+                warn!("rollback: rm {}", dst.display());
+                remove_path(dst)?;
+            }
+            Action::Remove { path, stash, .. } => {
+                warn!("rollback: restore {}", path.display());
+                move_path(stash, path)?;
+            }
+            Action::Symlink { dst, .. } => {
+                warn!("rollback: rm {}", dst.display());
+                remove_path(dst)?;
+            }
+            Action::Rename { src, dst } => {
+                warn!("rollback: mv {} {}", dst.display(), src.display());
+                fs::rename(dst, src).context(IoError {path: dst.clone()})?;
+            }
+            Action::Write { path, stash, .. } => {
+                warn!("rollback: restore {}", path.display());
+                remove_path(path)?;
+                if let Some(stash) = stash {
+                    move_path(stash, path)?;
+                }
+            }
+            Action::Chmod { path, old_mode, .. } => {
+                warn!("rollback: chmod {:o} {}", old_mode, path.display());
+                fs::set_permissions(path, fs::Permissions::from_mode(*old_mode)).context(IoError {path: path.clone()})?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn revert(&self, fs: &FileUtils) -> Result<()> {
+        // like undo, but for saved journal: things may have changed since, so check before
+        // touching anything, and do everything through FileUtils
+        match self {
+            Action::Mkdir { path } => {
+                if path.is_dir() && path.read_dir().context(IoError {path: path.clone()})?.next().is_none() {
+                    fs.unlink(path)?;
+                }
+            }
+            Action::Copy { dst, hash, .. } => {
+                if fs::symlink_metadata(dst).is_ok() {
+                    check_unchanged(dst, hash)?;
+                    fs.unlink(dst)?;
+                }
+            }
+            Action::Symlink { src, dst } => {
+                if fs::read_link(dst).ok().as_ref() == Some(src) {
+                    fs.unlink(dst)?;
+                }
+                else {
+                    warn!("{} is not a link to {} anymore, skip", dst.display(), src.display());
+                }
+            }
+            Action::Rename { src, dst } => {
+                if fs::symlink_metadata(src).is_ok() {
+                    misc_error_file!("file exists, can not move back", src.clone())
+                }
+                fs.rename(dst, src)?;
+            }
+            Action::Remove { path, private: true, .. } => {
+                warn!("{} was not kept in journal, can not restore", path.display());
+            }
+            Action::Remove { path, stash, .. } => {
+                if fs::symlink_metadata(path).is_ok() {
+                    misc_error_file!("file exists, can not restore", path.clone())
+                }
+                fs.restore(stash, path)?;
+            }
+            Action::Write { path, stash, hash, private } => {
+                if fs::symlink_metadata(path).is_ok() {
+                    check_unchanged(path, hash)?;
+                    fs.unlink(path)?;
+                }
+                match stash {
+                    Some(_) if *private => warn!("old {} was not kept in journal, can not restore", path.display()),
+                    Some(stash) => fs.restore(stash, path)?,
+                    None => (),
+                }
+            }
+            Action::Chmod { path, old_mode, .. } => {
+                if path.exists() {
+                    fs.chmod(path, *old_mode)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stash(&self) -> Option<&PathBuf> {
+        match self {
+            Action::Remove { stash, .. } => Some(stash),
+            Action::Write { stash: Some(stash), .. } => Some(stash),
+            _ => None,
+        }
+    }
+
+    fn stash_mut(&mut self) -> Option<&mut PathBuf> {
+        match self {
+            Action::Remove { stash, .. } => Some(stash),
+            Action::Write { stash: Some(stash), .. } => Some(stash),
+            _ => None,
+        }
+    }
+
+    fn moved(&self) -> Option<(&PathBuf, &PathBuf)> {
+        // path moved elsewhere along with everything inside
+        match self {
+            Action::Remove { path, stash, .. } => Some((path, stash)),
+            Action::Rename { src, dst } => Some((src, dst)),
+            _ => None,
+        }
+    }
+
+    fn is_private(&self) -> bool {
+        matches!(self, Action::Remove { private: true, .. } | Action::Write { private: true, .. })
+    }
+}
+
+fn current_path(p: &Path, later: &[Action]) -> PathBuf {
+    // stash inside a dir that was removed later in the same run is in that dir's stash now
+    let mut p = p.to_path_buf();
+    for action in later {
+        if let Some((from, to)) = action.moved() {
+            if let Ok(rel) = p.strip_prefix(from) {
+                p = to.join(rel);
+            }
+        }
+    }
+    p
+}
+
+pub fn content_hash(p: &Path) -> Result<String> {
+    // sha256 of file, or of names and hashes of everything in dir
+    if ! p.is_dir() {
+        let content = fs::read(p).context(IoError {path: p})?;
+        return Ok(sha256(&content));
+    }
+    let mut entries = p.read_dir().context(IoError {path: p})?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>().context(IoError {path: p})?;
+    entries.sort();
+    let mut hashes = String::new();
+    for entry in entries {
+        hashes += &format!("{} {}\n", entry.file_name().unwrap().to_string_lossy(), content_hash(&entry)?);
+    }
+    Ok(sha256(hashes.as_bytes()))
+}
+
+fn check_unchanged(p: &Path, hash: &Option<String>) -> Result<()> {
+    if let Some(hash) = hash {
+        if content_hash(p)? != *hash {
+            misc_error_file!("file changed since, roll back later changes first", p.to_path_buf())
+        }
+    }
+    Ok(())
+}
+
+fn is_false(b: &bool) -> bool {
+    ! *b
+}
+
+fn remove_path(p: &PathBuf) -> Result<()> {
+    let meta = match fs::symlink_metadata(p) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(()), // nothing to remove
+    };
+    if meta.is_dir() {
+        fs::remove_dir_all(p).context(IoError {path: p.clone()})?;
+    }
+    else {
+        fs::remove_file(p).context(IoError {path: p.clone()})?;
+    }
+    Ok(())
+}
+
+fn move_path(from: &PathBuf, to: &PathBuf) -> Result<()> {
+    // rename, or copy and remove, if from and to are on different filesystems
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let meta = fs::symlink_metadata(from).context(IoError {path: from.clone()})?;
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(from).context(IoError {path: from.clone()})?;
+        std::os::unix::fs::symlink(&target, to).context(IoError {path: to.clone()})?;
+    }
+    else if meta.is_dir() {
+        fs::create_dir_all(to).context(IoError {path: to.clone()})?;
+        let mut options = fs_extra::dir::CopyOptions::new();
+        options.content_only = true;
+        fs_extra::dir::copy(from, to, &options).context(FsError {path: from.clone()})?;
+    }
+    else {
+        fs::copy(from, to).context(IoError {path: from.clone()})?;
+    }
+    remove_path(from)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub id: u64,
+    pub timestamp: String,
+    pub host: String,
+    pub command: String,
+    #[serde(default)]
+    pub rolled_back: bool,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+impl Record {
+    fn path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{}.toml", id))
+    }
+
+    pub fn load(dir: &Path, id: u64) -> Result<Self> {
+        let path = Self::path(dir, id);
+        if ! path.exists() {
+            misc_error!(format!("no such journal entry: {}", id))
+        }
+        let content = fs::read_to_string(&path).context(IoError {path: path.clone()})?;
+        match toml::from_str(&content) {
+            Ok(record) => Ok(record),
+            Err(e) => misc_error_file!(e, path),
+        }
+    }
+
+    pub fn list(dir: &Path) -> Result<Vec<Self>> {
+        let mut ids = Self::ids(dir)?;
+        ids.sort();
+        ids.into_iter().map(|id| Self::load(dir, id)).collect()
+    }
+
+    fn ids(dir: &Path) -> Result<Vec<u64>> {
+        if ! dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        Ok(dir.read_dir().context(IoError {path: dir})?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "toml"))
+            .filter_map(|p| p.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()))
+            .collect())
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = Self::path(dir, self.id);
+        let content = match toml::to_string(self) {
+            Ok(c) => c,
+            Err(e) => misc_error_file!(e, path.clone()),
+        };
+        fs::write(&path, content).context(IoError {path: path.clone()})?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Persist {
+    dir: PathBuf,
+    command: String,
+    host: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Journal {
+    actions: Vec<Action>,
+    persist: Option<Persist>,
+    data_dir: Option<PathBuf>,
+}
+
+impl Journal {
+    pub fn record(&mut self, action: Action) {
+        trace!("journal: {:?}", action);
+        self.actions.push(action);
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn persist(&mut self, dir: PathBuf, command: String, host: String) {
+        self.persist = Some(Persist {dir, command, host});
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        if ! self.actions.is_empty() {
+            if let Some(persist) = self.persist.take() {
+                return self.save(persist);
+            }
+        }
+        for action in self.actions.drain(..) {
+            if let Some(stash) = action.stash() {
+                trace!("drop stash {:?}", stash);
+                remove_path(stash)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, persist: Persist) -> Result<()> {
+        // actions are updated as stashes are moved, so a failed save can still be rolled back
+        let dir = persist.dir;
+        fs::create_dir_all(&dir).context(IoError {path: dir.clone()})?;
+        let id = Record::ids(&dir)?.into_iter().max().unwrap_or(0) + 1;
+        let data_dir = dir.join(id.to_string());
+        self.data_dir = Some(data_dir.clone());
+
+        for n in 0..self.actions.len() {
+            if self.actions[n].is_private() {
+                continue;
+            }
+            let stash = match self.actions[n].stash() {
+                Some(stash) => current_path(stash, &self.actions[(n+1)..]),
+                None => continue,
+            };
+            fs::create_dir_all(&data_dir).context(IoError {path: data_dir.clone()})?;
+            let kept = data_dir.join(n.to_string());
+            trace!("keep stash {:?} in {:?}", stash, kept);
+            move_path(&stash, &kept)?;
+            *self.actions[n].stash_mut().unwrap() = kept;
+        }
+        let record = Record {
+            id,
+            timestamp: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            host: persist.host,
+            command: persist.command,
+            rolled_back: false,
+            actions: self.actions.clone(),
+        };
+        record.save(&dir)?;
+        debug!("saved journal {}", id);
+
+        let actions = self.actions.drain(..).collect::<Vec<_>>();
+        for (n, action) in actions.iter().enumerate() {
+            if let (true, Some(stash)) = (action.is_private(), action.stash()) {
+                let stash = current_path(stash, &actions[(n+1)..]);
+                trace!("drop private stash {:?}", stash);
+                remove_path(&stash)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rollback(&mut self) -> Result<()> {
+        // undo everything we can, report first error
+        if ! self.actions.is_empty() {
+            warn!("rolling back {} action(s)", self.actions.len());
+        }
+        let mut result = Ok(());
+        while let Some(action) = self.actions.pop() {
+            if let Err(e) = action.undo() {
+                error!("rollback failed: {}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        // journal failed to save, stashes were moved back
+        if let Some(data_dir) = self.data_dir.take() {
+            if data_dir.read_dir().is_ok_and(|mut d| d.next().is_none()) {
+                let _ = fs::remove_dir(&data_dir);
+            }
+        }
+        result
+    }
+}
@@ -6,6 +6,9 @@ extern crate fs_extra;
 extern crate dirs;
 extern crate hostname;
 extern crate snafu;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 use clap::{Arg, App, SubCommand};
 
@@ -15,8 +18,14 @@ use snafu::*;
 #[macro_use]
 mod errors;
 mod app;
+mod meta;
 mod templates;
+mod functions;
 mod file_utils;
+mod journal;
+mod backups;
+mod config;
+mod crypt;
 
 fn main() -> Result<(), Box<dyn error::Error>> {
     let matches = App::new("confine")
@@ -37,6 +46,10 @@ fn main() -> Result<(), Box<dyn error::Error>> {
              .short("n")
              .help("dry run")
         )
+        .arg(Arg::with_name("strict")
+             .long("strict")
+             .help("fail on undefined template variables, instead of warning and leaving them empty")
+        )
         .arg(Arg::with_name("home")
              .long("home")
              .takes_value(true)
@@ -51,6 +64,10 @@ fn main() -> Result<(), Box<dyn error::Error>> {
         .subcommand(SubCommand::with_name("move")
             .aliases(&["mv"])
             .about("move file under config control")
+            .arg(Arg::with_name("encrypt")
+                 .long("encrypt")
+                 .help("store file encrypted, see [encryption] in tune/confine.toml")
+            )
             .arg(Arg::with_name("group")
                  .index(1)
                  .required(true)
@@ -69,6 +86,18 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                  .takes_value(true)
                  .help("template file")
             )
+            .arg(Arg::with_name("interactive")
+                 .long("interactive")
+                 .short("i")
+                 .help("ask what to do with files in home that are in the way of links")
+            )
+            .arg(Arg::with_name("on-conflict")
+                 .long("on-conflict")
+                 .takes_value(true)
+                 .possible_values(&["backup", "skip", "adopt", "fail"])
+                 .default_value("backup")
+                 .help("what to do with files in home that are in the way of links: overwrite after backup, keep them, take them into group, or stop")
+            )
             .arg(Arg::with_name("group")
                  .index(1)
                  .required(true)
@@ -80,6 +109,12 @@ fn main() -> Result<(), Box<dyn error::Error>> {
         )
         .subcommand(SubCommand::with_name("undo")
             .about("undo symlinking, restore original files")
+            .arg(Arg::with_name("template")
+                 .long("template")
+                 .short("t")
+                 .takes_value(true)
+                 .help("template file, for templates that were never processed")
+            )
             .arg(Arg::with_name("group")
                  .index(1)
                  .required(true)
@@ -97,6 +132,11 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                  .short("l")
                  .help("remove only link file (default is to remove both link and source)")
             )
+            .arg(Arg::with_name("yes")
+                 .long("yes")
+                 .short("y")
+                 .help("don't ask before removing template sections from control files")
+            )
             .arg(Arg::with_name("group")
                  .index(1)
                  .required(true)
@@ -106,10 +146,194 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                 .multiple(true)
             )
         )
+        .subcommand(SubCommand::with_name("status")
+            .aliases(&["st"])
+            .about("show link status of files, exit with error if any file is out of sync")
+            .arg(Arg::with_name("group")
+                 .index(1)
+                 .help("group (default is all groups)")
+            )
+            .arg(Arg::with_name("files")
+                .multiple(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("adopt")
+            .about("take content of files in home that replaced links back into group, and link them again")
+            .arg(Arg::with_name("diff")
+                 .long("diff")
+                 .short("d")
+                 .help("show difference and ask before adopting each file")
+            )
+            .arg(Arg::with_name("yes")
+                 .long("yes")
+                 .short("y")
+                 .help("don't ask, with --diff")
+            )
+            .arg(Arg::with_name("group")
+                 .index(1)
+                 .help("group (default is all groups)")
+            )
+            .arg(Arg::with_name("files")
+                .multiple(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("diff")
+            .about("show how files in home that are not links differ from the group, exit with error if any does")
+            .arg(Arg::with_name("template")
+                 .long("template")
+                 .short("t")
+                 .takes_value(true)
+                 .help("template file, for templates that were never processed")
+            )
+            .arg(Arg::with_name("group")
+                 .index(1)
+                 .help("group (default is all groups)")
+            )
+            .arg(Arg::with_name("files")
+                .multiple(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("backups")
+            .about("list files saved to backup before overwriting")
+            .arg(Arg::with_name("host")
+                 .long("host")
+                 .takes_value(true)
+                 .help("only this host (default is all hosts)")
+            )
+            .subcommand(SubCommand::with_name("prune")
+                .about("remove old backups")
+                .arg(Arg::with_name("keep")
+                     .long("keep")
+                     .takes_value(true)
+                     .help("generations of each file to keep (default is backup.keep from tune/confine.toml, or 5)")
+                )
+                .arg(Arg::with_name("host")
+                     .long("host")
+                     .takes_value(true)
+                     .help("only this host (default is all hosts)")
+                )
+            )
+        )
+        .subcommand(SubCommand::with_name("restore-backup")
+            .about("replace symlink with file from backup")
+            .arg(Arg::with_name("host")
+                 .long("host")
+                 .takes_value(true)
+                 .help("take backup of this host (default is current host)")
+            )
+            .arg(Arg::with_name("generation")
+                 .long("generation")
+                 .takes_value(true)
+                 .help("which backup to restore, see confine backups (default is the latest)")
+            )
+            .arg(Arg::with_name("files")
+                 .required(true)
+                 .multiple(true)
+                 .help("files in home dir, or absolute paths")
+            )
+        )
+        .subcommand(SubCommand::with_name("template")
+            .about("inspect templates")
+            .subcommand(SubCommand::with_name("vars")
+                .about("show variables for template and where they come from")
+                .arg(Arg::with_name("template")
+                     .long("template")
+                     .short("t")
+                     .takes_value(true)
+                     .help("template file")
+                )
+                .arg(Arg::with_name("file")
+                     .index(1)
+                     .required(true)
+                     .help("group/file")
+                )
+            )
+            .subcommand(SubCommand::with_name("refresh")
+                .about("process again templates whose source or variables changed since")
+            )
+            .subcommand(SubCommand::with_name("check")
+                .about("check control files: syntax, sections for unknown files, templates without section, unused variables")
+            )
+            .subcommand(SubCommand::with_name("render")
+                .about("print processed template without writing anything")
+                .arg(Arg::with_name("template")
+                     .long("template")
+                     .short("t")
+                     .takes_value(true)
+                     .help("template file")
+                )
+                .arg(Arg::with_name("diff")
+                     .long("diff")
+                     .short("d")
+                     .help("show difference from currently processed file instead")
+                )
+                .arg(Arg::with_name("file")
+                     .index(1)
+                     .required(true)
+                     .help("group/file")
+                )
+            )
+        )
+        .subcommand(SubCommand::with_name("group")
+            .about("manage groups")
+            .subcommand(SubCommand::with_name("new")
+                .about("create empty group")
+                .arg(Arg::with_name("name")
+                     .index(1)
+                     .required(true)
+                     .help("group")
+                )
+            )
+            .subcommand(SubCommand::with_name("list")
+                .aliases(&["ls"])
+                .about("list groups with number of files and their link status")
+            )
+            .subcommand(SubCommand::with_name("rename")
+                .aliases(&["mv"])
+                .about("rename group and re-point links to its files")
+                .arg(Arg::with_name("name")
+                     .index(1)
+                     .required(true)
+                     .help("group")
+                )
+                .arg(Arg::with_name("new_name")
+                     .index(2)
+                     .required(true)
+                     .help("new name")
+                )
+            )
+            .subcommand(SubCommand::with_name("rm")
+                .about("undo links to group files and remove group")
+                .arg(Arg::with_name("yes")
+                     .long("yes")
+                     .short("y")
+                     .help("don't ask before removing group and template sections from control files")
+                )
+                .arg(Arg::with_name("name")
+                     .index(1)
+                     .required(true)
+                     .help("group")
+                )
+            )
+        )
+        .subcommand(SubCommand::with_name("history")
+            .about("list previous runs of move, link, undo and delete")
+            .arg(Arg::with_name("verbose")
+                 .short("v")
+                 .help("show changed files")
+            )
+        )
+        .subcommand(SubCommand::with_name("rollback")
+            .about("revert changes made by a previous run, see history")
+            .arg(Arg::with_name("id")
+                 .index(1)
+                 .required(true)
+                 .help("run id from history")
+            )
+        )
         .get_matches();
 
-    let mut app = app::Confine::new(&matches);
-    if let Err(err) = app.run(&matches) {
+    if let Err(err) = app::Confine::new(&matches).and_then(|mut app| app.run(&matches)) {
         if let Some(backtrace) = ErrorCompat::backtrace(&err) {
             println!("{}", backtrace);
         }
@@ -0,0 +1,190 @@
+extern crate toml;
+
+use std::path::{Path, PathBuf};
+use std::io::{self, BufRead};
+use std::fs;
+
+use app::Group;
+use file_utils::FileUtils;
+
+use snafu::*;
+use errors::*;
+
+/*
+per-group manifest, meta.toml:
+
+version = 1
+
+[[files]]
+path = ".bashrc"
+
+[[files]]
+path = ".gitconfig"
+template = true
+hosts = ["work-laptop"]
+
+[[files]]
+path = ".netrc"
+encrypted = true
+
+old plain meta.txt (one path per line) is migrated on first read
+*/
+
+const META_FILE: &str = "meta.toml";
+const LEGACY_META_FILE: &str = "meta.txt";
+const META_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    #[default]
+    Symlink,
+    Copy,
+}
+
+impl LinkMode {
+    fn is_default(&self) -> bool {
+        *self == LinkMode::default()
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    ! *b
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "LinkMode::is_default")]
+    pub link: LinkMode,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub template: bool,
+    // stored encrypted in group, see crypt.rs
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub encrypted: bool,
+    // octal, e.g. "0600"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    // link location, if it differs from path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    // hostnames this entry applies to, empty means all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+}
+
+impl Entry {
+    pub fn new(path: &Path) -> Self {
+        Entry {path: path.to_path_buf(), ..Default::default()}
+    }
+
+    pub fn mode_bits(&self) -> Result<Option<u32>> {
+        match self.mode {
+            None => Ok(None),
+            Some(ref mode) => match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+                Ok(bits) => Ok(Some(bits)),
+                Err(_) => misc_error_file!(format!("invalid mode {}", mode), self.path.clone()),
+            }
+        }
+    }
+
+    pub fn applies_to(&self, hostname: &str) -> bool {
+        self.hosts.is_empty() || self.hosts.iter().any(|h| h == hostname)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    #[serde(default)]
+    files: Vec<Entry>,
+}
+
+pub struct Meta {
+    fs: FileUtils,
+    meta_file: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl Meta {
+    pub fn new(group: &Group, fs: &FileUtils) -> Result<Self> {
+        let meta_file = group.abs_path().join(META_FILE);
+        let legacy_file = group.abs_path().join(LEGACY_META_FILE);
+        let fs = fs.clone();
+        if meta_file.exists() {
+            let entries = Self::read(&meta_file)?;
+            return Ok(Self {fs, meta_file, entries})
+        }
+        if ! legacy_file.exists() {
+            return Ok(Self {fs, meta_file, entries: Vec::new()})
+        }
+
+        warn!("migrate {} to {}", legacy_file.display(), meta_file.display());
+        let entries = io::BufReader::new(fs::File::open(&legacy_file).context(IoError {path: legacy_file.clone()})?)
+            .lines()
+            .map(|l| l.unwrap())
+            .filter(|l| ! l.is_empty())
+            .map(|l| Entry::new(&PathBuf::from(l)))
+            .collect::<Vec<_>>();
+        let meta = Self {fs, meta_file, entries};
+        meta.save()?;
+        meta.fs.unlink(&legacy_file)?;
+        Ok(meta)
+    }
+
+    pub fn exists_in(dir: &Path) -> bool {
+        dir.join(META_FILE).is_file() || dir.join(LEGACY_META_FILE).is_file()
+    }
+
+    fn read(meta_file: &PathBuf) -> Result<Vec<Entry>> {
+        let content = fs::read_to_string(meta_file).context(IoError {path: meta_file.clone()})?;
+        let manifest: Manifest = match toml::from_str(&content) {
+            Ok(m) => m,
+            Err(e) => misc_error_file!(e, meta_file.clone()),
+        };
+        if manifest.version > META_VERSION {
+            misc_error_file!(format!("unsupported manifest version {}, upgrade confine", manifest.version), meta_file.clone())
+        }
+        Ok(manifest.files)
+    }
+
+    pub fn add(&mut self, entry: Entry) -> Result<()> {
+        trace!("{:?} add {:?}", self.meta_file, entry);
+        if self.check(&entry.path) {
+            trace!("no new entries for meta");
+            return Ok(());
+        }
+        self.entries.push(entry);
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        self.save()?;
+
+        Ok(())
+    }
+    pub fn delete(&mut self, entry: &PathBuf) -> Result<()> {
+        self.entries.retain(|e| e.path != *entry);
+        self.save()?;
+
+        Ok(())
+    }
+    pub fn save(&self) -> Result<()> {
+        trace!("new meta: {:?}", self.entries);
+        let manifest = Manifest {version: META_VERSION, files: self.entries.clone()};
+        let content = match toml::to_string(&manifest) {
+            Ok(c) => c,
+            Err(e) => misc_error_file!(e, self.meta_file.clone()),
+        };
+        self.fs.write(&self.meta_file, &content)?;
+
+        Ok(())
+    }
+    pub fn list(&self) -> Result<Vec<String>> {
+        Ok(self.entries.iter().map(|e| e.path.display().to_string()).collect())
+    }
+    pub fn check(&self, entry: &PathBuf) -> bool {
+        // is entry in meta?
+        self.get(entry).is_some()
+    }
+    pub fn get(&self, entry: &PathBuf) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.path == *entry)
+    }
+}
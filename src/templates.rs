@@ -1,9 +1,19 @@
 extern crate toml;
+extern crate libc;
+extern crate sha2;
+extern crate toml_edit;
 
 use std::fs;
+use std::fmt;
+use std::process::Command;
 
-use std::path::PathBuf;
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet, BTreeMap};
+
+use config::TemplatesConfig;
+use file_utils::FileUtils;
+use functions;
+use app::{hostname, username};
 
 use snafu::*;
 use errors::*;
@@ -12,73 +22,252 @@ use errors::*;
 /*
 files in tune/templates - "control files"
 files in groups - "templates"
+files in tune/templates/partials - shared fragments and macros for {% include %} and {% import %},
+named by path relative to partials dir
+
+variables for template are merged from (later wins):
+  built-in variables (HOME, HOSTNAME, ..., see builtins())
+  [templates.vars] in tune/confine.toml
+  [_defaults] section of control file
+  section of control file for the template
+  environment variables CONFINE_VAR_<NAME>
+
+values can refer to secrets kept out of the storage, at any depth:
+  TOKEN = { secret = "npm_token" }         key in secrets file ([templates] secrets, default
+                                           ~/.config/confine/secrets.toml)
+  TOKEN = { secret_env = "NPM_TOKEN" }     environment variable
+  TOKEN = { secret_command = "pass npm" }  stdout of shell command
+processed files with secrets are readable only by owner and listed in processed/.gitignore
+
+processed files are kept per host, so machines sharing the storage don't overwrite each other's:
+tune/templates/processed/<hostname>/<group>/<file>, or the same under [templates] processed dir
+
+for every processed file processed/.state.toml keeps, per host, the control
+file it was made with and hashes of its inputs, to find and re-render stale files:
+
+[hosts.laptop."common/.gitconfig"]
+control = "home"
+source = "<sha256 of template>"
+vars = "<sha256 of control file, tune/confine.toml and partials>"
 */
 
+const DEFAULTS_SECTION: &str = "_defaults";
+const ENV_PREFIX: &str = "CONFINE_VAR_";
+const STATE_FILE: &str = ".state.toml";
+const DEFAULT_SECRETS_FILE: &str = ".config/confine/secrets.toml";
+
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub value: toml::Value,
+    // where it comes from
+    pub source: String,
+    // value has secrets in it
+    pub secret: bool,
+}
+
+pub type Variables = BTreeMap<String, Variable>;
+
+// control file, parsed
+struct ControlFile {
+    defaults: toml::value::Table,
+    sections: BTreeMap<String, toml::value::Table>, // template file => variables
+}
+
+// found by `template check`
+pub enum Problem {
+    // control file can not be loaded
+    Invalid { error: ::errors::Error },
+    // section for file that is in no group's meta
+    UnknownFile { control_file: PathBuf, template_name: String },
+    // file in meta that can not be read, e.g. missing from group dir
+    Unreadable { control_file: PathBuf, template_name: String, error: ::errors::Error },
+    // template in meta without section in any control file
+    Uncovered { template_name: PathBuf },
+    // variable that is not used by template, or by any template of control file for [_defaults]
+    UnusedVariable { control_file: PathBuf, section: String, name: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Invalid {error} => write!(f, "{}", error),
+            Problem::UnknownFile {control_file, template_name} =>
+                write!(f, "{}: [\"{}\"] is not in meta of any group", control_file.display(), template_name),
+            Problem::Unreadable {control_file, template_name, error} =>
+                write!(f, "{}: [\"{}\"] {}", control_file.display(), template_name, error),
+            Problem::Uncovered {template_name} =>
+                write!(f, "{}: template has no section in any control file", template_name.display()),
+            Problem::UnusedVariable {control_file, section, name} =>
+                write!(f, "{}: [\"{}\"] variable {} is not used", control_file.display(), section, name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Processed {
+    pub control: String,
+    source: String,
+    vars: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    hosts: BTreeMap<String, BTreeMap<String, Processed>>,
+}
+
 pub struct Templates {
     root: PathBuf,
     inited: bool,
     control_files: HashMap<PathBuf, Vec<PathBuf>>, // control file => template files
     templates: HashMap<PathBuf, Vec<PathBuf>>, // template => control files
     vars: HashMap<PathBuf, HashMap<PathBuf, toml::value::Table>>, // control file => { template_file => variables }
+    defaults: HashMap<PathBuf, toml::value::Table>, // control file => [_defaults]
     home: PathBuf,
+    config: TemplatesConfig,
+    // undefined variables are errors, not warnings
+    strict: bool,
+    fs: FileUtils,
 }
 
 impl Templates {
-    pub fn new(root: PathBuf, home: PathBuf) -> Self {
+    pub fn new(root: PathBuf, home: PathBuf, config: TemplatesConfig, strict: bool, fs: FileUtils) -> Self {
         Self {
             root: root,
             inited: false,
             control_files: HashMap::new(),
             templates: HashMap::new(),
             vars: HashMap::new(),
+            defaults: HashMap::new(),
             home: home,
+            config,
+            strict,
+            fs,
         }
     }
 
-    fn init(&mut self) {
+    pub fn select(&self) -> Option<String> {
+        // control file for this host from tune/confine.toml
+        let (host, user, os) = (hostname(), username(), std::env::consts::OS);
+        let rule = self.config.select.iter().find(|r| r.matches(&host, &user, os))?;
+        debug!("control file {} selected for {}@{} ({})", rule.control, user, host, os);
+        Some(rule.control.clone())
+    }
+
+    fn init(&mut self) -> Result<()> {
 
         if self.inited {
-            return
+            return Ok(())
         }
 
         self.inited = true;
         
+        for cfile in self.control_file_paths()? {
+            let control = load_control_file(&cfile)?;
+            trace!("{:?} -- {:?}", cfile, control.sections.keys().collect::<Vec<_>>());
+            self.defaults.insert(cfile.clone(), control.defaults);
+            self.control_files.insert(cfile.clone(), control.sections.keys().map(PathBuf::from).collect());
+            for (f, vars) in control.sections {
+                let p = PathBuf::from(f);
+                self.templates.entry(p.clone()).or_default().push(cfile.clone());
+                self.vars.entry(cfile.clone()).or_default().insert(p, vars);
+            }
+        }
+        Ok(())
+    }
+
+    fn control_file_paths(&self) -> Result<Vec<PathBuf>> {
+        // *.toml in tune/templates, sorted
         let tdir = self.root.join("tune/templates");
         trace!("templates init in {:?}", tdir);
         if ! tdir.is_dir() {
             trace!("no template dir {:?}", tdir);
-            return;
-        }
-        let control_files = tdir.read_dir().unwrap().map(|f| f.unwrap().path()).collect::<Vec<_>>();
-        for cfile in control_files {
-            if cfile.extension().map_or(true, |e| e != "toml") {
-                continue
-            }
-            trace!("load template control file {:?}", cfile);
-            let t = fs::read_to_string(&cfile).unwrap().parse::<toml::Value>().unwrap();
-            let table = t.as_table().unwrap();
-            trace!("{:?}", table);
-            trace!("{:?} -- {:?}", cfile, table.keys().map(PathBuf::from).collect::<Vec<_>>());
-            self.control_files.insert(cfile.clone(), table.keys().map(PathBuf::from).collect());
-            for f in table.keys() {
-                let p = PathBuf::from(f);
-                self.templates.entry(p.clone()).or_insert_with(Vec::new).push(cfile.clone());
-                // self.vars.insert(p.clone(), table.get(f).unwrap().as_table().unwrap().clone());
-                self.vars.entry(cfile.clone()).or_insert_with(HashMap::new).insert(p.clone(), table.get(f).unwrap().as_table().unwrap().clone());
+            return Ok(Vec::new());
+        }
+        let mut paths = Vec::new();
+        for entry in tdir.read_dir().context(IoError {path: tdir.clone()})? {
+            let path = entry.context(IoError {path: tdir.clone()})?.path();
+            if path.is_file() && path.extension().is_some_and(|e| e == "toml") {
+                paths.push(path);
             }
         }
+        paths.sort();
+        Ok(paths)
     }
 
-    pub fn needs_template(&mut self, file: &PathBuf) -> bool {
-        self.init();
+    pub fn needs_template(&mut self, file: &PathBuf) -> Result<bool> {
+        self.init()?;
         if let Some(cfiles) = self.templates.get(file) {
             debug!("{} is a template file: required by {:?}", file.display(), cfiles);
-            return true;
+            return Ok(true);
         }
-        false
+        Ok(false)
     }
 
-    pub fn process(&mut self, template_name: &PathBuf, file: &PathBuf, control: &str) -> Result<PathBuf> {
+    pub fn check(&self, templates: &[(PathBuf, bool)]) -> Result<Vec<Problem>> {
+        // problems in control files; templates are all files in meta of all groups, and whether
+        // they are marked as template there
+        let mut problems = Vec::new();
+        let mut covered = HashSet::new();
+        let partials = self.partials()?.into_iter()
+            .map(|(path, _)| fs::read_to_string(&path).context(IoError {path: path.clone()}))
+            .collect::<Result<Vec<_>>>()?;
+        let partials = partials.iter().map(|p| tera_code(p)).collect::<Vec<_>>().join("\n");
+        for cfile in self.control_file_paths()? {
+            let control = match load_control_file(&cfile) {
+                Ok(control) => control,
+                Err(error) => {
+                    problems.push(Problem::Invalid {error});
+                    continue;
+                }
+            };
+            let name = cfile.strip_prefix(&self.root).unwrap_or(&cfile).to_path_buf();
+            // code of all templates of control file, for [_defaults]
+            let mut all_code = partials.clone();
+            for (template_name, vars) in control.sections.iter() {
+                covered.insert(PathBuf::from(template_name));
+                if ! templates.iter().any(|(t, _)| t == Path::new(template_name)) {
+                    problems.push(Problem::UnknownFile {control_file: name.clone(), template_name: template_name.clone()});
+                    continue;
+                }
+                let file = self.root.join(template_name);
+                let content = match fs::read_to_string(&file).context(IoError {path: file.clone()}) {
+                    Ok(content) => content,
+                    Err(error) => {
+                        problems.push(Problem::Unreadable {control_file: name.clone(), template_name: template_name.clone(), error});
+                        continue;
+                    }
+                };
+                let code = tera_code(&content) + "\n" + &partials;
+                all_code += "\n";
+                all_code += &code;
+                for var in vars.keys().filter(|v| locate(&code, v).is_none()) {
+                    problems.push(Problem::UnusedVariable {control_file: name.clone(), section: template_name.clone(), name: var.clone()});
+                }
+            }
+            for var in control.defaults.keys().filter(|v| locate(&all_code, v).is_none()) {
+                problems.push(Problem::UnusedVariable {control_file: name.clone(), section: DEFAULTS_SECTION.to_string(), name: var.clone()});
+            }
+        }
+        for (template_name, _) in templates.iter().filter(|(t, is_template)| *is_template && ! covered.contains(t)) {
+            problems.push(Problem::Uncovered {template_name: template_name.clone()});
+        }
+        Ok(problems)
+    }
+
+    pub fn processed_path(&self, template_name: &PathBuf) -> PathBuf {
+        self.processed_dir().join(hostname()).join(template_name)
+    }
+
+    fn processed_dir(&self) -> PathBuf {
+        match self.config.processed {
+            Some(ref dir) => self.home.join(dir.strip_prefix("~/").unwrap_or(dir)),
+            None => self.root.join("tune/templates/processed"),
+        }
+    }
+
+    fn control_file(&mut self, control: &str) -> Result<PathBuf> {
+        self.init()?;
         let control = if control.ends_with(".toml") {
             control.to_string()
         }
@@ -95,41 +284,681 @@ impl Templates {
         if control_file.is_none() {
             return misc_error!(format!("template description not found: {}", control))
         }
-        let control_file = control_file.unwrap().canonicalize().context(IoError {path: control_file.unwrap()})?;
-        
-        debug!("process template config {} with variables from {}", file.display(), control_file.display());
+        control_file.unwrap().canonicalize().context(IoError {path: control_file.unwrap()})
+    }
+
+    pub fn variables(&mut self, template_name: &PathBuf, control: &str) -> Result<Variables> {
+        let control_file = self.control_file(control)?;
+        let control_name = control_file.strip_prefix(&self.root).unwrap_or(&control_file).display().to_string();
+
+        let file_vars = self.vars.get(&control_file);
+        if file_vars.is_none() {
+            return misc_error!(format!("no variables found for file {} in template descripiton {}", template_name.display(), control_file.display()))
+        }
+        let file_vars = file_vars.unwrap().get(template_name);
+        if file_vars.is_none() {
+            return misc_error!(format!("variables for file {} missing in {}", template_name.display(), control_file.display()))
+        }
+
+        let mut vars = Variables::new();
+        let mut add = |table: &toml::value::Table, source: &str| {
+            for (key, val) in table.iter() {
+                vars.insert(key.clone(), Variable {value: val.clone(), source: source.to_string(), secret: false});
+            }
+        };
+        add(&self.builtins(template_name), "built-in");
+        add(&self.config.vars, "tune/confine.toml [templates.vars]");
+        if let Some(defaults) = self.defaults.get(&control_file) {
+            add(defaults, &format!("{} [{}]", control_name, DEFAULTS_SECTION));
+        }
+        add(file_vars.unwrap(), &format!("{} [\"{}\"]", control_name, template_name.display()));
+        let env = std::env::vars()
+            .filter(|(k, _)| k.starts_with(ENV_PREFIX) && k.len() > ENV_PREFIX.len())
+            .map(|(k, v)| (k[ENV_PREFIX.len()..].to_string(), toml::Value::String(v)))
+            .collect::<toml::value::Table>();
+        add(&env, "environment");
+
+        let mut secret_env = HashSet::new();
+        for var in vars.values() {
+            secret_env_names(&var.value, &mut secret_env);
+        }
+        for (name, var) in vars.iter_mut() {
+            trace!("resolve secrets in {}", name);
+            var.secret = self.resolve_secrets(&mut var.value)?;
+            // env table and CONFINE_ variables have secret_env values in them as is
+            var.secret |= match (name.as_str(), &var.value) {
+                ("env", toml::Value::Table(env)) => env.keys().any(|k| secret_env.contains(k)),
+                _ => var.source == "environment" && secret_env.contains(&format!("{}{}", ENV_PREFIX, name)),
+            };
+        }
+
+        Ok(vars)
+    }
+
+    fn resolve_secrets(&self, value: &mut toml::Value) -> Result<bool> {
+        // replace secret references with secrets, true if there were any
+        let reference = match value {
+            toml::Value::Table(table) if table.len() == 1 => {
+                let (kind, arg) = table.iter().next().unwrap();
+                match arg.as_str() {
+                    Some(arg) if kind.starts_with("secret") => Some((kind.clone(), arg.to_string())),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some((kind, arg)) = reference {
+            *value = toml::Value::String(self.secret(&kind, &arg)?);
+            return Ok(true);
+        }
+        let mut found = false;
+        match value {
+            toml::Value::Table(table) => for (_, v) in table.iter_mut() {
+                found |= self.resolve_secrets(v)?;
+            },
+            toml::Value::Array(array) => for v in array.iter_mut() {
+                found |= self.resolve_secrets(v)?;
+            },
+            _ => (),
+        }
+        Ok(found)
+    }
+
+    fn secrets_file(&self) -> PathBuf {
+        match self.config.secrets {
+            Some(ref file) => self.home.join(file.strip_prefix("~/").unwrap_or(file)),
+            None => self.home.join(DEFAULT_SECRETS_FILE),
+        }
+    }
+
+    fn secret(&self, kind: &str, name: &str) -> Result<String> {
+        match kind {
+            "secret" => {
+                let path = self.secrets_file();
+                let content = fs::read_to_string(&path).context(IoError {path: path.clone()})?;
+                let secrets = match content.parse::<toml::Value>() {
+                    Ok(secrets) => secrets,
+                    Err(e) => misc_error_file!(e, path.clone()),
+                };
+                match secrets.get(name).and_then(|s| s.as_str()) {
+                    Some(secret) => Ok(secret.to_string()),
+                    None => misc_error_file!(format!("secret {} not found", name), path),
+                }
+            }
+            "secret_env" => match std::env::var(name) {
+                Ok(secret) => Ok(secret),
+                Err(_) => misc_error!(format!("secret environment variable {} is not set", name)),
+            },
+            "secret_command" => {
+                debug!("run secret command {}", name);
+                let output = Command::new("sh").arg("-c").arg(name).output().context(IoError {path: PathBuf::from("sh")})?;
+                if ! output.status.success() {
+                    misc_error!(format!("secret command `{}` failed: {}", name, String::from_utf8_lossy(&output.stderr).trim()))
+                }
+                Ok(String::from_utf8_lossy(&output.stdout).trim_end_matches('\n').to_string())
+            }
+            _ => misc_error!(format!("unknown secret reference {}, expected secret, secret_env or secret_command", kind)),
+        }
+    }
+
+    fn builtins(&self, template_name: &Path) -> toml::value::Table {
+        let string = |s: &str| toml::Value::String(s.to_string());
+        let path = |p: &PathBuf| string(&p.display().to_string());
+        let xdg = |name: &str, default: &str| match std::env::var(name) {
+            Ok(dir) if ! dir.is_empty() => string(&dir),
+            _ => path(&self.home.join(default)),
+        };
+        let group = template_name.iter().next().map_or(String::new(), |g| g.to_string_lossy().to_string());
+
+        let mut vars = toml::value::Table::new();
+        vars.insert("HOME".to_string(), path(&self.home));
+        vars.insert("HOSTNAME".to_string(), string(&hostname()));
+        vars.insert("USER".to_string(), string(&username()));
+        // safe: getuid and getgid can not fail
+        vars.insert("UID".to_string(), toml::Value::Integer(unsafe { libc::getuid() } as i64));
+        vars.insert("GID".to_string(), toml::Value::Integer(unsafe { libc::getgid() } as i64));
+        vars.insert("OS".to_string(), string(std::env::consts::OS));
+        vars.insert("DISTRO".to_string(), string(&distro()));
+        vars.insert("ARCH".to_string(), string(std::env::consts::ARCH));
+        vars.insert("ROOT".to_string(), path(&self.root));
+        vars.insert("GROUP".to_string(), string(&group));
+        vars.insert("XDG_CONFIG_HOME".to_string(), xdg("XDG_CONFIG_HOME", ".config"));
+        vars.insert("XDG_DATA_HOME".to_string(), xdg("XDG_DATA_HOME", ".local/share"));
+        vars.insert("XDG_CACHE_HOME".to_string(), xdg("XDG_CACHE_HOME", ".cache"));
+        vars.insert("XDG_STATE_HOME".to_string(), xdg("XDG_STATE_HOME", ".local/state"));
+        vars.insert("env".to_string(), toml::Value::Table(std::env::vars().map(|(k, v)| (k, toml::Value::String(v))).collect()));
+        vars
+    }
+
+    pub fn render(&mut self, template_name: &PathBuf, file: &PathBuf, control: &str) -> Result<String> {
+        // processed content of template, nothing is written
+        Ok(self.render_secret(template_name, file, control)?.0)
+    }
+
+    fn render_secret(&mut self, template_name: &PathBuf, file: &PathBuf, control: &str) -> Result<(String, bool)> {
+        // processed content, and whether there are secrets in it
+        let vars = self.variables(template_name, control)?;
+
+        debug!("process template config {} with variables from {}", file.display(), control);
 
         trace!("read {:?}", file);
-        let file_str = fs::read_to_string(&file).context(IoError {path: file})?;
+        let file_str = fs::read_to_string(file).context(IoError {path: file})?;
         let mut context = tera::Context::new();
-        let vars = self.vars.get(&control_file);
-        if vars.is_none() {
-            return misc_error!(format!("no variables found for file {} in template descripiton {}", file.display(), control_file.display()))
+        for (key, var) in vars.iter() {
+            if ! var.secret {
+                trace!("{} - {:?}", key, var.value);
+            }
+            context.insert(key, &tera_value(&var.value));
+        }
+        let mut tera = tera::Tera::default();
+        tera.autoescape_on(Vec::new());
+        functions::register(&mut tera, &self.home);
+        let name = template_name.display().to_string();
+        // template sources by name, to point at undefined variables
+        let mut sources = HashMap::new();
+        sources.insert(name.clone(), (file.clone(), file_str.clone()));
+        let partials = self.partials()?;
+        for (path, partial) in partials.iter() {
+            let content = fs::read_to_string(path).context(IoError {path: path.clone()})?;
+            sources.insert(partial.clone(), (path.clone(), content));
+        }
+        let error = |template_name: &PathBuf, e: tera::Error| {
+            let details = describe(&e, template_name, &sources);
+            Err::<(), _>(e).context(TemplateError {template_name: template_name.clone(), control, details}).unwrap_err()
+        };
+        if let Err(e) = tera.add_template_files(partials.into_iter().map(|(path, name)| (path, Some(name)))) {
+            return Err(error(&self.partials_dir(), e));
+        }
+        if let Err(e) = tera.add_raw_template(&name, &file_str) {
+            return Err(error(file, e));
+        }
+
+        // undefined variables are replaced with empty strings one by one, to find all of them
+        let mut undefined = Vec::new();
+        let processed = loop {
+            match tera.render(&name, &context) {
+                Ok(processed) => break processed,
+                Err(e) => {
+                    let (var, location) = match undefined_variable(&e, &sources) {
+                        Some(found) => found,
+                        None => return Err(error(file, e)),
+                    };
+                    if undefined.iter().any(|(v, _)| *v == var) || ! define_empty(&mut context, &var) {
+                        return Err(error(file, e));
+                    }
+                    if ! self.strict {
+                        warn!("{}: variable {} is not defined in {}, using empty string", location, var, control);
+                    }
+                    undefined.push((var, location));
+                }
+            }
+        };
+        if self.strict && ! undefined.is_empty() {
+            let names = undefined.iter().map(|(v, l)| format!("{} ({})", v, l)).collect::<Vec<_>>().join(", ");
+            return TemplateUndefined {template_name: file.clone(), control, names}.fail();
+        }
+        let secret = vars.values().any(|v| v.secret);
+        if ! secret {
+            trace!("{}", processed);
+        }
+        Ok((processed, secret))
+    }
+
+    fn inputs(&mut self, template_name: &PathBuf, file: &PathBuf, control: &str) -> Result<Processed> {
+        // what processed file depends on
+        let control_file = self.control_file(control)?;
+        let mut vars = fs::read(&control_file).context(IoError {path: control_file.clone()})?;
+        let config = self.root.join("tune/confine.toml");
+        if config.exists() {
+            vars.extend(fs::read(&config).context(IoError {path: config.clone()})?);
+        }
+        for (path, name) in self.partials()? {
+            vars.extend(name.into_bytes());
+            vars.extend(fs::read(&path).context(IoError {path: path.clone()})?);
+        }
+        let source = fs::read(file).context(IoError {path: file.clone()})?;
+        trace!("inputs of {:?}: {:?}, {:?}", template_name, file, control_file);
+        Ok(Processed {control: control.to_string(), source: sha256(&source), vars: sha256(&vars)})
+    }
+
+    fn partials_dir(&self) -> PathBuf {
+        self.root.join("tune/templates/partials")
+    }
+
+    fn partials(&self) -> Result<Vec<(PathBuf, String)>> {
+        // files in partials dir and their names for tera, sorted by name
+        let dir = self.partials_dir();
+        let mut partials = Vec::new();
+        if dir.is_dir() {
+            walk_partials(&dir, &dir, &mut partials)?;
+        }
+        partials.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(partials)
+    }
+
+    fn ignore_processed(&self, template_name: &Path) -> Result<()> {
+        // keep processed file with secrets out of git
+        let path = self.processed_dir().join(".gitignore");
+        let line = format!("/{}/{}", hostname(), template_name.display());
+        let content = if path.exists() {
+            fs::read_to_string(&path).context(IoError {path: path.clone()})?
+        }
+        else {
+            String::new()
+        };
+        if content.lines().any(|l| l == line) {
+            return Ok(());
         }
-        let vars = vars.unwrap().get(template_name);
-        if vars.is_none() {
-            return misc_error!(format!("variables for file {} missing in {}", file.display(), control_file.display()))
+        self.fs.write(&path, content + &line + "\n")
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.processed_dir().join(STATE_FILE)
+    }
+
+    fn load_state(&self) -> Result<State> {
+        let path = self.state_path();
+        if ! path.exists() {
+            return Ok(State::default());
         }
-        let mut vars = vars.unwrap().clone();
-        if vars.get("HOME").is_none() {
-            vars.insert("HOME".to_string(), toml::Value::String(self.home.to_str().unwrap().to_string()));
+        let content = fs::read_to_string(&path).context(IoError {path: path.clone()})?;
+        match toml::from_str(&content) {
+            Ok(state) => Ok(state),
+            Err(e) => misc_error_file!(e, path),
+        }
+    }
+
+    fn save_state(&self, state: &State) -> Result<()> {
+        let path = self.state_path();
+        let content = match toml::to_string(state) {
+            Ok(c) => c,
+            Err(e) => misc_error_file!(e, path.clone()),
+        };
+        self.fs.write(&path, content)
+    }
+
+    pub fn last_processed(&self, template_name: &Path) -> Result<Option<Processed>> {
+        // how template was processed on this host last time
+        let mut state = self.load_state()?;
+        Ok(state.hosts.remove(&hostname()).and_then(|mut files| files.remove(&template_name.display().to_string())))
+    }
+
+    pub fn is_stale(&mut self, template_name: &PathBuf) -> Result<bool> {
+        // template, control file or global vars changed since it was processed on this host
+        let last = match self.last_processed(template_name)? {
+            Some(last) => last,
+            None => return Ok(false),
+        };
+        let file = self.root.join(template_name);
+        if ! file.exists() {
+            return Ok(false);
         }
-        for (key, val) in vars.iter() {
-            trace!("{} - {:?}", key, val);
-            context.insert(key, val.as_str().unwrap());
+        if ! self.processed_path(template_name).exists() {
+            return Ok(true);
         }
-        let processed = tera::Tera::one_off(&file_str, &context, false).context(TemplateError { template_name: file })?;
-        trace!("{}", processed);
+        match self.inputs(template_name, &file, &last.control) {
+            Ok(inputs) => Ok(inputs != last),
+            Err(e) => {
+                debug!("{}: {}", template_name.display(), e);
+                Ok(true)
+            }
+        }
+    }
 
-        let tdir = self.root.join("tune/templates/processed/");
-        let tdir = tdir.join(template_name.parent().unwrap());
-        // TODO dry?
-        fs::create_dir_all(&tdir).context(IoError {path: tdir.clone()})?;
-        let processed_file = tdir.join(template_name.file_name().unwrap());
+    pub fn forget(&self, template_name: &Path) -> Result<()> {
+        // template is deleted, drop it from state of all hosts
+        let mut state = self.load_state()?;
+        let name = template_name.display().to_string();
+        let mut changed = false;
+        for files in state.hosts.values_mut() {
+            changed |= files.remove(&name).is_some();
+        }
+        if changed {
+            self.save_state(&state)?;
+        }
+        Ok(())
+    }
 
-        trace!("write to {:?}", processed_file);
-        fs::write(&processed_file, &processed).context(IoError {path: processed_file.clone()})?;
+    pub fn control_files(&mut self) -> Result<Vec<PathBuf>> {
+        self.init()?;
+        let mut files = self.control_files.keys().cloned().collect::<Vec<_>>();
+        files.sort();
+        Ok(files)
+    }
 
+    pub fn processed_group_dirs(&self, group: &Path) -> Result<Vec<PathBuf>> {
+        // processed files of group, dir for each host
+        let dir = self.processed_dir();
+        if ! dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut dirs = Vec::new();
+        for entry in dir.read_dir().context(IoError {path: dir.clone()})? {
+            let path = entry.context(IoError {path: dir.clone()})?.path().join(group);
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    pub fn rename_group(&mut self, old: &Path, new: &Path) -> Result<()> {
+        // state and .gitignore of processed files after group is renamed, control files are
+        // loaded again
+        let rename = |name: &str| match Path::new(name).strip_prefix(old) {
+            Ok(rest) => new.join(rest).display().to_string(),
+            Err(_) => name.to_string(),
+        };
+        let mut state = self.load_state()?;
+        for files in state.hosts.values_mut() {
+            *files = std::mem::take(files).into_iter().map(|(name, processed)| (rename(&name), processed)).collect();
+        }
+        if self.state_path().exists() {
+            self.save_state(&state)?;
+        }
+        let path = self.processed_dir().join(".gitignore");
+        if path.exists() {
+            let content = fs::read_to_string(&path).context(IoError {path: path.clone()})?;
+            // lines are /<host>/<group>/<file>
+            let lines = content.lines().map(|l| match l.trim_start_matches('/').split_once('/') {
+                Some((host, name)) => format!("/{}/{}", host, rename(name)),
+                None => l.to_string(),
+            });
+            self.fs.write(&path, lines.map(|l| l + "\n").collect::<String>())?;
+        }
+        self.inited = false;
+        self.control_files.clear();
+        self.templates.clear();
+        self.vars.clear();
+        self.defaults.clear();
+        Ok(())
+    }
+
+    pub fn control_files_for(&mut self, template_name: &PathBuf) -> Result<Vec<PathBuf>> {
+        // control files that have a section for template
+        self.init()?;
+        Ok(self.templates.get(template_name).cloned().unwrap_or_default())
+    }
+
+    pub fn refresh(&mut self, group: Option<&Path>) -> Result<Vec<PathBuf>> {
+        // re-render stale processed files of this host (and group) with the control file used last time
+        let names = self.load_state()?.hosts.remove(&hostname()).unwrap_or_default();
+        let mut refreshed = Vec::new();
+        for (name, processed) in names {
+            let template_name = PathBuf::from(name);
+            if group.is_some_and(|g| ! template_name.starts_with(g)) || ! self.is_stale(&template_name)? {
+                continue;
+            }
+            let file = self.root.join(&template_name);
+            if ! file.exists() {
+                warn!("{} is gone, skip", file.display());
+                continue;
+            }
+            debug!("refresh {} with {}", template_name.display(), processed.control);
+            self.process(&template_name, &file, &processed.control)?;
+            refreshed.push(template_name);
+        }
+        Ok(refreshed)
+    }
+
+    pub fn write_processed(&self, template_name: &PathBuf, content: &[u8], private: bool) -> Result<PathBuf> {
+        // private files are readable only by owner and kept out of git
+        let processed_file = self.processed_path(template_name);
+        let tdir = processed_file.parent().unwrap().to_owned();
+        if ! tdir.exists() {
+            self.fs.mkpath(&tdir)?;
+        }
+        if private {
+            self.fs.write_private(&processed_file, content)?;
+            self.ignore_processed(template_name)?;
+        }
+        else {
+            self.fs.write(&processed_file, content)?;
+        }
         Ok(processed_file)
     }
+
+    pub fn process(&mut self, template_name: &PathBuf, file: &PathBuf, control: &str) -> Result<PathBuf> {
+        let inputs = self.inputs(template_name, file, control)?;
+        let (processed, secret) = self.render_secret(template_name, file, control)?;
+        let processed_file = self.write_processed(template_name, processed.as_bytes(), secret)?;
+
+        let mut state = self.load_state()?;
+        state.hosts.entry(hostname()).or_default().insert(template_name.display().to_string(), inputs);
+        self.save_state(&state)?;
+
+        Ok(processed_file)
+    }
+}
+
+fn load_control_file(path: &PathBuf) -> Result<ControlFile> {
+    trace!("load template control file {:?}", path);
+    let content = fs::read_to_string(path).context(IoError {path: path.clone()})?;
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => misc_error_file!("not a table", path.clone()),
+        Err(e) => misc_error_file!(e.to_string().trim_end(), path.clone()),
+    };
+    let mut control = ControlFile {defaults: toml::value::Table::new(), sections: BTreeMap::new()};
+    for (key, value) in table {
+        let vars = match value {
+            toml::Value::Table(vars) => vars,
+            _ => misc_error_file!(format!("[\"{}\"] must be a table of variables", key), path.clone()),
+        };
+        if key == DEFAULTS_SECTION {
+            control.defaults = vars;
+        }
+        else {
+            control.sections.insert(key, vars);
+        }
+    }
+    Ok(control)
+}
+
+fn tera_code(content: &str) -> String {
+    // insides of {{ }} and {% %}, where variables can be used
+    let mut code = String::new();
+    let mut rest = content;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        let end = match after.chars().next() {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            _ => {
+                rest = after;
+                continue;
+            }
+        };
+        let (inside, tail) = after[1..].split_once(end).unwrap_or((&after[1..], ""));
+        code += inside;
+        code += "\n";
+        rest = tail;
+    }
+    code
+}
+
+fn distro() -> String {
+    // ID from /etc/os-release (ubuntu, arch, fedora, ...), or OS name where there is none
+    let id = fs::read_to_string("/etc/os-release").ok().and_then(|release| {
+        release.lines()
+            .find(|l| l.starts_with("ID="))
+            .map(|l| l["ID=".len()..].trim_matches('"').to_string())
+    });
+    id.unwrap_or_else(|| std::env::consts::OS.to_string())
+}
+
+fn tera_value(value: &toml::Value) -> toml::Value {
+    // datetimes would get to tera as serde's private wrapper struct, so they go as strings
+    match value {
+        toml::Value::Datetime(datetime) => toml::Value::String(datetime.to_string()),
+        toml::Value::Array(array) => toml::Value::Array(array.iter().map(tera_value).collect()),
+        toml::Value::Table(table) => toml::Value::Table(table.iter().map(|(k, v)| (k.clone(), tera_value(v))).collect()),
+        value => value.clone(),
+    }
+}
+
+fn secret_env_names(value: &toml::Value, names: &mut HashSet<String>) {
+    // NAME of every { secret_env = "NAME" } in value
+    match value {
+        toml::Value::Table(table) => for (k, v) in table.iter() {
+            match (k.as_str(), v) {
+                ("secret_env", toml::Value::String(name)) if table.len() == 1 => { names.insert(name.clone()); }
+                _ => secret_env_names(v, names),
+            }
+        },
+        toml::Value::Array(array) => for v in array.iter() {
+            secret_env_names(v, names);
+        },
+        _ => (),
+    }
+}
+
+pub fn remove_section(control_file: &PathBuf, template_name: &Path) -> Result<String> {
+    // content of control file without section for template, formatting and comments are kept
+    let content = fs::read_to_string(control_file).context(IoError {path: control_file.clone()})?;
+    let mut doc = match content.parse::<toml_edit::DocumentMut>() {
+        Ok(doc) => doc,
+        Err(e) => misc_error_file!(e, control_file.clone()),
+    };
+    doc.as_table_mut().remove(&template_name.display().to_string());
+    Ok(doc.to_string())
+}
+
+pub fn rename_sections(control_file: &PathBuf, old: &Path, new: &Path) -> Result<Option<String>> {
+    // content of control file with sections for files of group old moved to group new, None if
+    // there are no such sections
+    let content = fs::read_to_string(control_file).context(IoError {path: control_file.clone()})?;
+    let mut doc = match content.parse::<toml_edit::DocumentMut>() {
+        Ok(doc) => doc,
+        Err(e) => misc_error_file!(e, control_file.clone()),
+    };
+    let names = doc.as_table().iter()
+        .filter_map(|(name, _)| Path::new(name).strip_prefix(old).ok().map(|rest| (name.to_string(), new.join(rest))))
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        return Ok(None);
+    }
+    for (name, new_name) in names {
+        let section = doc.as_table_mut().remove(&name).unwrap();
+        doc.as_table_mut().insert(&new_name.display().to_string(), section);
+    }
+    Ok(Some(doc.to_string()))
+}
+
+fn error_chain(e: &tera::Error) -> Vec<String> {
+    let mut chain = vec![e.to_string()];
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        chain.push(e.to_string());
+        source = e.source();
+    }
+    chain
+}
+
+fn undefined_variable(e: &tera::Error, sources: &HashMap<String, (PathBuf, String)>) -> Option<(String, String)> {
+    // name of undefined variable and where it is used: file:line:column
+    let message = error_chain(e).into_iter().find(|m| m.starts_with("Variable `") && m.contains("` not found in context"))?;
+    let var = message["Variable `".len()..].split('`').next()?.to_string();
+    let template = message.split("while rendering '").nth(1)?.split('\'').next()?;
+    let location = match sources.get(template) {
+        Some((path, content)) => match locate(content, &var) {
+            Some((line, col)) => format!("{}:{}:{}", path.display(), line, col),
+            None => path.display().to_string(),
+        },
+        None => template.to_string(),
+    };
+    Some((var, location))
+}
+
+fn locate(content: &str, var: &str) -> Option<(usize, usize)> {
+    // first use of variable in template, 1-based line and column
+    let root = var.split(['.', '[']).next().unwrap();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    for (n, line) in content.lines().enumerate() {
+        for (idx, _) in line.match_indices(root) {
+            let before = line[..idx].chars().next_back();
+            let after = line[idx + root.len()..].chars().next();
+            if ! before.is_some_and(is_word) && ! after.is_some_and(is_word) {
+                return Some((n + 1, line[..idx].chars().count() + 1));
+            }
+        }
+    }
+    None
+}
+
+fn describe(e: &tera::Error, path: &Path, sources: &HashMap<String, (PathBuf, String)>) -> String {
+    // file:line:column: tera error with its causes
+    if let Some((var, location)) = undefined_variable(e, sources) {
+        return format!("{}: variable {} is not defined", location, var);
+    }
+    let mut chain = error_chain(e);
+    let mut path = path.display().to_string();
+    if chain.len() > 1 && chain[0].starts_with("Failed to ") {
+        // Failed to parse 'name' for template or "path" for partial file
+        let first = chain.remove(0);
+        match first.split(['\'', '"']).nth(1) {
+            Some(name) if sources.contains_key(name) => path = sources[name].0.display().to_string(),
+            Some(name) if name.starts_with('/') => path = name.to_string(),
+            _ => (),
+        }
+    }
+    // parse errors are " --> line:column", code snippet, "= message"
+    let last = chain.pop().unwrap();
+    let last = match last.trim_start().strip_prefix("--> ") {
+        Some(rest) => {
+            let location = rest.lines().next().unwrap_or_default();
+            let message = rest.lines().last().unwrap_or_default().trim_start_matches([' ', '=']);
+            path = format!("{}:{}", path, location);
+            message.to_string()
+        }
+        None => last,
+    };
+    chain.push(last);
+    format!("{}: {}", path, chain.join(": "))
+}
+
+fn define_empty(context: &mut tera::Context, var: &str) -> bool {
+    // set variable, possibly nested (a.b.c), to empty string; false if it can't be done
+    if var.contains('[') {
+        return false;
+    }
+    let mut parts = var.split('.');
+    let root = parts.next().unwrap();
+    let rest = parts.collect::<Vec<_>>();
+    if rest.is_empty() {
+        context.insert(root, "");
+        return true;
+    }
+    let mut value = context.get(root).cloned().unwrap_or_else(|| tera::Value::Object(tera::Map::new()));
+    let mut node = &mut value;
+    for (i, part) in rest.iter().enumerate() {
+        let object = match node {
+            tera::Value::Object(object) => object,
+            _ => return false,
+        };
+        let default = if i + 1 == rest.len() { tera::Value::String(String::new()) } else { tera::Value::Object(tera::Map::new()) };
+        node = object.entry(part.to_string()).or_insert(default);
+    }
+    context.insert(root, &value);
+    true
+}
+
+fn walk_partials(dir: &Path, base: &Path, partials: &mut Vec<(PathBuf, String)>) -> Result<()> {
+    for entry in dir.read_dir().context(IoError {path: dir})? {
+        let path = entry.context(IoError {path: dir})?.path();
+        if path.is_dir() {
+            walk_partials(&path, base, partials)?;
+        }
+        else {
+            let name = path.strip_prefix(base).unwrap().display().to_string();
+            partials.push((path, name));
+        }
+    }
+    Ok(())
+}
+
+pub fn sha256(data: &[u8]) -> String {
+    use self::sha2::Digest;
+    sha2::Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
 }
@@ -2,10 +2,11 @@
 
 import pytest
 from pathlib import Path
-import os, sys
+import os, sys, socket, pwd
 from shutil import copytree, rmtree
 import subprocess
 import shlex
+import tomllib
 
 """
 
@@ -25,17 +26,24 @@ os.chdir(test_root)
 
 home_test = Path(test_root, 'home_test')
 common = Path(test_root, 'common')
-meta = Path(common, 'meta.txt')
+meta = Path(common, 'meta.toml')
 backup = Path(test_root, 'backup')
+journal = Path(test_root, 'journal')
 tune = Path(test_root, 'tune')
+processed = Path(tune, 'templates/processed')
+processed_host = Path(processed, socket.gethostname())
+# outside of home, to test absolute paths
+abs_test = Path(test_root, 'abs_test')
 
 def setup():
-    for d in [home_test, common, backup]:
+    for d in [home_test, common, backup, journal, abs_test, processed]:
         if d.exists():
             rmtree(d)
 
     copytree(Path(test_root, 'home'), home_test)
     common.mkdir()
+    abs_test.mkdir()
+    Path(abs_test, 'hosts').write_text('127.0.0.1 localhost')
     
 
 confine_exe = Path(test_root, '../target/debug/confine').absolute()
@@ -46,10 +54,16 @@ def confine(*args):
     print(' '.join(args))
     subprocess.run(args, check=True)
 
+def confine_output(*args, input=''):
+    args = [ shlex.quote(str(arg)) for arg in args ]
+    args = [str(confine_exe), '--home', str(home_test), *args]
+    print(' '.join(args))
+    return subprocess.run(args, check=True, capture_output=True, text=True, input=input).stdout
+
 def get_meta(meta_file=None):
     meta_file = meta_file or meta
-    with open(meta_file) as f:
-        return set([ s.strip() for s in f ])
+    with open(meta_file, 'rb') as f:
+        return set([ e['path'] for e in tomllib.load(f)['files'] ])
 
 def test_add_file():
     setup()
@@ -91,6 +105,78 @@ def test_add_nested_file():
     assert m == {'.config/test_file', '.config/test_dir'}
 
 
+def test_invalid_group():
+    setup()
+
+    for args in [('mv', 'tune', '.test_conf'), ('mv', '.x', '.test_conf'), ('ln', 'journal')]:
+        res = subprocess.run([str(confine_exe), '--home', str(home_test), *args], capture_output=True, text=True)
+        # error, not panic
+        assert res.returncode == 1
+        assert 'Invalid group name' in res.stdout + res.stderr
+    assert not Path(home_test, '.test_conf').is_symlink()
+
+
+def test_meta_migrate():
+    setup()
+    confine('mv', 'common', '.test_conf')
+    meta.unlink()
+    Path(common, 'meta.txt').write_text('.test_conf\n.gitconfig\n')
+
+    confine('status', 'common', '.test_conf')
+    assert not Path(common, 'meta.txt').exists()
+    assert get_meta() == {'.test_conf', '.gitconfig'}
+
+
+def test_meta_attributes():
+    setup()
+    confine('mv', 'common', '.test_conf', '.config/test_file')
+    meta.write_text("""
+version = 1
+
+[[files]]
+path = ".test_conf"
+link = "copy"
+mode = "0600"
+
+[[files]]
+path = ".config/test_file"
+target = ".config/test_file_renamed"
+
+[[files]]
+path = ".nope"
+hosts = ["no-such-host"]
+""")
+    Path(home_test, '.test_conf').unlink()
+    Path(home_test, '.config/test_file').unlink()
+    confine('ln', 'common')
+
+    test_conf = Path(home_test, '.test_conf')
+    assert test_conf.exists() and not test_conf.is_symlink()
+    assert test_conf.stat().st_mode & 0o777 == 0o600
+
+    renamed = Path(home_test, '.config/test_file_renamed')
+    assert renamed.is_symlink()
+    assert renamed.resolve() == Path(common, '.config/test_file')
+
+    confine('status', 'common')
+
+    # copy with the same content is not overwritten again
+    confine('ln', 'common', '--on-conflict', 'fail')
+    assert not backup.exists() or not any(backup.rglob('.test_conf*'))
+    test_conf.write_text('changed\n')
+    res = subprocess.run([str(confine_exe), '--home', str(home_test), 'status', 'common'], capture_output=True, text=True)
+    assert res.returncode != 0
+    assert any(l.startswith('not a link') and l.endswith('common/.test_conf') for l in res.stdout.splitlines())
+    confine('ln', 'common')
+    assert test_conf.read_text() == Path(common, '.test_conf').read_text()
+    assert len(list(backup.rglob('.test_conf*'))) == 1
+
+    # .nope is not for this host
+    confine('undo', 'common')
+    assert not renamed.is_symlink()
+    assert renamed.read_text() == Path(common, '.config/test_file').read_text()
+
+
 def test_ln():
     setup()
     confine('mv', 'common', '.test_conf')
@@ -164,7 +250,7 @@ def test_ln_backup():
     assert backup.exists()
     host = next(backup.iterdir())
 
-    with open(Path(host, '.test_conf')) as f:
+    with open(next(host.glob('.test_conf~*'))) as f:
         t = f.read()
         assert t == 'oh shi'
 
@@ -190,11 +276,485 @@ def test_ln_backup_dir():
     
     host = next(backup.iterdir())
     
-    f = Path(host, '.config/test_dir/test_file')
+    f = Path(next(host.glob('.config/test_dir~*')), 'test_file')
 
     assert f.exists()
 
 
+def test_absolute():
+    setup()
+
+    hosts = Path(abs_test, 'hosts')
+    src = Path(common, str(hosts)[1:])
+
+    confine('mv', 'common', hosts)
+    assert str(hosts) in get_meta()
+    assert hosts.is_symlink()
+    assert hosts.resolve() == src
+
+    confine('status', 'common', hosts)
+
+    hosts.unlink()
+    hosts.write_text('oh shi')
+    confine('ln', 'common', hosts)
+    assert hosts.resolve() == src
+    assert next(next(backup.iterdir()).glob(str(hosts)[1:] + '~*')).read_text() == 'oh shi'
+
+    confine('undo', 'common', hosts)
+    assert not hosts.is_symlink()
+    assert hosts.read_text() == '127.0.0.1 localhost'
+
+    hosts.unlink()
+    confine('ln', 'common', hosts)
+    confine('rm', 'common', hosts)
+    assert not hosts.exists()
+    assert not src.exists()
+    assert str(hosts) not in get_meta()
+
+
+def test_rollback_on_error():
+    setup()
+
+    # second file does not exist, first one must be left untouched
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('mv', 'common', '.test_conf', '.no_such_file')
+    test_conf = Path(home_test, '.test_conf')
+    assert test_conf.exists()
+    assert not test_conf.is_symlink()
+    assert not Path(common, '.test_conf').exists()
+    assert not meta.exists()
+
+    confine('mv', 'common', '.test_conf', '.test_dir')
+    Path(home_test, '.test_conf').unlink()
+    test_dir = Path(home_test, '.test_dir')
+    test_dir.unlink()
+    test_dir.mkdir()
+    rmtree(Path(common, '.test_dir'))
+
+    # .test_conf is linked first, then .test_dir fails
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('ln', 'common', '.test_conf', '.test_dir')
+    assert not test_conf.exists()
+    assert test_dir.is_dir()
+    assert not test_dir.is_symlink()
+    assert not backup.exists() or not any(backup.iterdir())
+    assert [p.name for p in home_test.iterdir() if 'confine~' in p.name] == []
+
+
+def test_history_rollback():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    test_dir = Path(home_test, '.test_dir')
+
+    confine('mv', 'common', '.test_conf', '.test_dir')
+    confine('status')
+    # dry run is not recorded
+    confine('-n', 'rm', 'common', '.test_dir')
+    confine('rm', 'common', '.test_dir')
+    assert not test_dir.exists()
+    assert get_meta() == {'.test_conf'}
+
+    confine('history', '-v')
+    assert len(list(journal.glob('*.toml'))) == 2
+
+    confine('rollback', '2')
+    assert test_dir.is_symlink()
+    assert Path(test_dir, 'foo').exists()
+    assert get_meta() == {'.test_conf', '.test_dir'}
+    confine('status')
+
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('rollback', '2')
+
+    confine('rollback', '1')
+    assert not test_conf.is_symlink()
+    assert not test_dir.is_symlink()
+    assert Path(test_dir, 'foo').exists()
+    assert not Path(common, '.test_conf').exists()
+
+    # rollback of rollback
+    confine('rollback', '4')
+    assert test_dir.is_symlink()
+    assert test_conf.is_symlink()
+    confine('status')
+
+
+def test_journal_save_fails():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    journal.mkdir()
+    Path(journal, '1').write_text('in the way')
+
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('mv', 'common', '.test_conf')
+    assert not test_conf.is_symlink()
+    assert test_conf.is_file()
+    assert not meta.exists()
+    assert list(journal.glob('*.toml')) == []
+    assert [p.name for p in home_test.iterdir() if 'confine~' in p.name] == []
+
+
+def test_rollback_changed_since():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    test_dir = Path(home_test, '.test_dir')
+
+    confine('mv', 'common', '.test_conf')
+    confine('mv', 'common', '.test_dir')
+
+    # meta.toml was changed by the second run
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('rollback', '1')
+    assert test_conf.is_symlink()
+    assert get_meta() == {'.test_conf', '.test_dir'}
+    confine('status')
+
+    confine('rollback', '2')
+    confine('rollback', '1')
+    assert not test_conf.is_symlink()
+    assert not test_dir.is_symlink()
+    assert not meta.exists()
+
+    # group file was edited through the link
+    confine('mv', 'common', '.test_conf')
+    test_conf.write_text('edited\n')
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('rollback', '5')
+    assert test_conf.is_symlink()
+    assert test_conf.read_text() == 'edited\n'
+
+
+def test_restore_backup():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    confine('mv', 'common', '.test_conf')
+    test_conf.unlink()
+    test_conf.write_text('oh shi')
+    confine('ln', 'common', '.test_conf')
+    assert test_conf.is_symlink()
+
+    confine('backups')
+
+    confine('-n', 'restore-backup', '.test_conf')
+    assert test_conf.is_symlink()
+
+    confine('restore-backup', '.test_conf')
+    assert not test_conf.is_symlink()
+    assert test_conf.read_text() == 'oh shi'
+
+    # not a symlink anymore, refuse to overwrite
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('restore-backup', '.test_conf')
+
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('restore-backup', '.test_dir')
+
+
+def test_backup_generations():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    confine('mv', 'common', '.test_conf')
+    for i in range(3):
+        test_conf.unlink()
+        test_conf.write_text(str(i))
+        confine('ln', 'common', '.test_conf')
+
+    host = next(backup.iterdir())
+    generations = sorted(host.glob('.test_conf~*'))
+    assert len(generations) == 3
+    assert generations[-1].read_text() == '2'
+
+    confine('backups')
+    confine('restore-backup', '--generation', generations[0].name.split('~')[1], '.test_conf')
+    assert test_conf.read_text() == '0'
+
+    confine('backups', 'prune', '--keep', '1')
+    assert list(host.glob('.test_conf~*')) == [generations[-1]]
+
+    # retention from config
+    config = Path(tune, 'confine.toml')
+    config.write_text('[backup]\nkeep = 2\n')
+    try:
+        for i in range(3):
+            test_conf.unlink()
+            test_conf.write_text(str(i))
+            confine('ln', 'common', '.test_conf')
+        assert len(list(host.glob('.test_conf~*'))) == 2
+    finally:
+        config.unlink()
+
+
+def test_diff():
+    setup()
+
+    confine('mv', 'common', '.test_conf')
+    confine('mv', 'common', '.test_dir')
+    confine('mv', 'common', '.config/test_dir')
+    # links are not compared
+    assert confine_output('diff') == ''
+
+    confine('undo', 'common', '.test_conf', '.test_dir')
+    assert confine_output('diff', 'common') == ''
+
+    test_conf = Path(home_test, '.test_conf')
+    test_conf.write_text('changed\n')
+    Path(home_test, '.test_dir/foo').write_text('new\n')
+    Path(home_test, '.test_dir/bar').write_bytes(b'\x00\xff')
+    args = [str(confine_exe), '--home', str(home_test), 'diff', 'common']
+    res = subprocess.run(args, capture_output=True, text=True)
+    assert res.returncode != 0
+    lines = res.stdout.splitlines()
+    assert '--- common/.test_conf' in lines
+    assert '+++ %s' % test_conf in lines
+    assert '+changed' in lines
+    assert '--- common/.test_dir/foo' in lines
+    assert '+new' in lines
+    assert 'Binary files /dev/null and %s differ' % Path(home_test, '.test_dir/bar') in lines
+    assert '3 file(s) differ' in res.stdout + res.stderr
+
+    # only given files
+    res = subprocess.run(args + ['.test_conf'], capture_output=True, text=True)
+    assert 'common/.test_dir/foo' not in res.stdout
+    assert '+changed' in res.stdout.splitlines()
+
+
+def replace_link(path, content=None):
+    # what editors do on save
+    target = path.resolve()
+    path.unlink()
+    if target.is_dir():
+        copytree(target, path)
+    else:
+        path.write_text(content)
+
+
+def test_adopt():
+    setup()
+
+    confine('mv', 'common', '.test_conf')
+    confine('mv', 'common', '.test_dir')
+    test_conf = Path(home_test, '.test_conf')
+    test_dir = Path(home_test, '.test_dir')
+    replace_link(test_conf, 'edited\n')
+    replace_link(test_dir)
+    Path(test_dir, 'bar').write_text('bar\n')
+
+    # not asked without terminal
+    confine('adopt', 'common', '--diff')
+    assert not test_conf.is_symlink()
+    assert Path(common, '.test_conf').read_text() == ''
+
+    confine('adopt', 'common', '--diff', '--yes', '.test_conf')
+    assert test_conf.is_symlink()
+    assert Path(common, '.test_conf').read_text() == 'edited\n'
+    assert not test_dir.is_symlink()
+
+    confine('adopt')
+    assert test_dir.is_symlink()
+    assert Path(common, '.test_dir/bar').read_text() == 'bar\n'
+    assert Path(common, '.test_dir/foo').exists()
+    confine('status', 'common')
+
+    # nothing to adopt
+    confine('adopt', 'common')
+    assert confine_output('diff') == ''
+
+
+def confine_tty(*args, input=''):
+    # stdin is a terminal, so confine asks questions
+    master, slave = os.openpty()
+    os.write(master, input.encode())
+    args = [str(confine_exe), '--home', str(home_test), *args]
+    try:
+        return subprocess.run(args, stdin=slave, capture_output=True, text=True, timeout=10)
+    finally:
+        os.close(master)
+        os.close(slave)
+
+
+def test_link_conflict():
+    setup()
+
+    confine('mv', 'common', '.test_conf')
+    test_conf = Path(home_test, '.test_conf')
+    group_conf = Path(common, '.test_conf')
+    replace_link(test_conf, 'home\n')
+
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('ln', 'common', '--on-conflict', 'fail')
+    assert not test_conf.is_symlink()
+
+    confine('ln', 'common', '--on-conflict', 'skip')
+    assert not test_conf.is_symlink()
+
+    confine('ln', 'common', '--on-conflict', 'adopt')
+    assert test_conf.is_symlink()
+    assert group_conf.read_text() == 'home\n'
+
+    # default is backup and overwrite
+    replace_link(test_conf, 'home2\n')
+    confine('ln', 'common')
+    assert test_conf.is_symlink()
+    assert group_conf.read_text() == 'home\n'
+
+    # diff, then keep home version
+    replace_link(test_conf, 'home3\n')
+    res = confine_tty('ln', '-i', 'common', input='d\nk\n')
+    assert res.returncode == 0
+    assert '+home3' in res.stdout.splitlines()
+    assert test_conf.is_symlink()
+    assert group_conf.read_text() == 'home3\n'
+
+    replace_link(test_conf, 'home4\n')
+    res = confine_tty('ln', '-i', 'common', input='a\n')
+    assert res.returncode != 0
+    assert not test_conf.is_symlink()
+
+    # without terminal --on-conflict is used
+    confine('ln', '-i', 'common', '--on-conflict', 'skip')
+    assert not test_conf.is_symlink()
+
+
+def test_adopt_template_encrypted():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    confine('mv', '--encrypt', 'common', '.config/test_file')
+    confine('ln', 'common', '-t', 'test2')
+    gitconfig = Path(home_test, '.gitconfig')
+    test_file = Path(home_test, '.config/test_file')
+
+    # templates are not adopted
+    replace_link(gitconfig, 'edited\n')
+    confine('adopt', 'common', '.gitconfig')
+    assert not gitconfig.is_symlink()
+    assert Path(common, '.gitconfig').read_text() == '{{HOME}}\n{{MY_VAR}}\n'
+
+    # encrypted files are encrypted again
+    replace_link(test_file, 'secret\n')
+    confine('adopt', 'common', '.config/test_file')
+    assert test_file.is_symlink()
+    assert test_file.read_text() == 'secret\n'
+    assert 'secret' not in Path(common, '.config/test_file').read_text()
+
+
+def test_diff_template():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    confine('ln', 'common', '-t', 'test2')
+    confine('undo', 'common', '.gitconfig')
+    # rendered with the control file used last time
+    assert confine_output('diff', 'common', '.gitconfig') == ''
+
+    Path(home_test, '.gitconfig').write_text('%s\n2\n' % home_test)
+    args = [str(confine_exe), '--home', str(home_test), 'diff', 'common', '.gitconfig']
+    res = subprocess.run(args, capture_output=True, text=True)
+    assert res.returncode != 0
+    lines = res.stdout.splitlines()
+    assert '--- common/.gitconfig (test2)' in lines
+    assert '-1' in lines and '+2' in lines
+
+
+def test_group():
+    setup()
+
+    extra = Path(test_root, 'extra')
+    renamed = Path(test_root, 'renamed')
+    controls = { c: c.read_text() for c in Path(tune, 'templates').glob('*.toml') }
+    try:
+        with pytest.raises(subprocess.CalledProcessError):
+            confine('group', 'new', 'tune')
+        confine('group', 'new', 'extra')
+        assert Path(extra, 'meta.toml').exists()
+        with pytest.raises(subprocess.CalledProcessError):
+            confine('group', 'new', 'extra')
+
+        confine('mv', 'common', '.test_conf')
+        confine('mv', 'common', '.test_dir')
+        confine('mv', 'common', '.gitconfig')
+        confine('ln', 'common', '-t', 'test2')
+        Path(home_test, '.test_dir').unlink()
+
+        lines = confine_output('group', 'list').splitlines()
+        assert 'common             3 file(s), 2 linked, 1 out of sync' in lines
+        assert 'extra              0 file(s), 0 linked, 0 out of sync' in lines
+
+        confine('ln', 'common', '.test_dir')
+        confine('group', 'rename', 'common', 'renamed')
+        assert not common.exists()
+        assert Path(home_test, '.test_conf').resolve() == Path(renamed, '.test_conf').resolve()
+        assert Path(home_test, '.test_dir').resolve() == Path(renamed, '.test_dir').resolve()
+        assert Path(home_test, '.gitconfig').resolve() == Path(processed_host, 'renamed/.gitconfig').resolve()
+        assert '["renamed/.gitconfig"]' in Path(tune, 'templates/test2.toml').read_text()
+        # processed with the same control file, not stale
+        confine('status', 'renamed')
+
+        with pytest.raises(subprocess.CalledProcessError):
+            confine('group', 'rename', 'renamed', 'extra')
+
+        # not confirmed without terminal
+        confine('group', 'rm', 'renamed')
+        assert renamed.exists()
+
+        confine('group', 'rm', 'renamed', '--yes')
+        assert not renamed.exists()
+        gitconfig = Path(home_test, '.gitconfig')
+        assert not gitconfig.is_symlink()
+        assert gitconfig.read_text().splitlines() == [str(home_test), '1']
+        assert not Path(home_test, '.test_dir').is_symlink()
+        assert Path(home_test, '.test_dir/foo').exists()
+        assert not Path(processed_host, 'renamed/.gitconfig').exists()
+        assert 'renamed/.gitconfig' not in Path(tune, 'templates/test2.toml').read_text()
+    finally:
+        for c, content in controls.items():
+            c.write_text(content)
+        for d in [extra, renamed]:
+            if d.exists():
+                rmtree(d)
+
+
+def test_group_rename_rollback():
+    setup()
+
+    renamed = Path(test_root, 'renamed')
+    test_conf = Path(home_test, '.test_conf')
+    gitconfig = Path(home_test, '.gitconfig')
+    controls = { c: c.read_text() for c in Path(tune, 'templates').glob('*.toml') }
+    try:
+        confine('mv', 'common', '.test_conf', '.gitconfig')
+        confine('ln', 'common', '-t', 'test2')
+        meta.unlink()
+        Path(common, 'meta.txt').write_text('.test_conf\n.gitconfig\n')
+
+        confine('group', 'rename', 'common', 'renamed')
+        assert not common.exists()
+        assert Path(renamed, 'meta.toml').exists()
+        assert [p.name for p in renamed.iterdir() if 'confine~' in p.name] == []
+        assert test_conf.resolve() == Path(renamed, '.test_conf').resolve()
+        # group is moved, not copied to journal
+        assert not Path(journal, '3', '.test_conf').exists()
+
+        confine('rollback', '3')
+        assert not renamed.exists()
+        assert Path(common, 'meta.txt').exists()
+        assert test_conf.resolve() == Path(common, '.test_conf').resolve()
+        assert gitconfig.resolve() == Path(processed_host, 'common/.gitconfig').resolve()
+        assert 'common/.gitconfig' in Path(processed, '.state.toml').read_text()
+        # processed with the same control file, not stale
+        confine('status', 'common')
+    finally:
+        for c, content in controls.items():
+            c.write_text(content)
+        if renamed.exists():
+            rmtree(renamed)
+
+
 ####### templates
 
 def test_templates():
@@ -208,7 +768,7 @@ def test_templates():
 
     with pytest.raises(Exception):
         # variable missing
-        confine('ln', 'common', '-t', 'test')
+        confine('--strict', 'ln', 'common', '-t', 'test')
 
     confine('ln', 'common', '-t', 'test2')
     confine('ln', 'common', '-t', 'test2.toml')
@@ -224,6 +784,522 @@ def test_templates():
 
 
 
+def test_templates_select():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    gitconfig = Path(home_test, '.gitconfig')
+    config = Path(tune, 'confine.toml')
+    try:
+        config.write_text("""
+[[templates.select]]
+host = "no-such-host-*"
+control = "test"
+
+[[templates.select]]
+host = "*"
+os = "%s"
+control = "test2"
+""" % sys.platform.replace('darwin', 'macos'))
+        confine('ln', 'common')
+        assert gitconfig.read_text().splitlines()[1] == '1'
+
+        # explicit -t wins for inspection
+        out = confine_output('template', 'render', 'common/.gitconfig', '-t', 'test')
+        assert out.splitlines()[1] == ''
+        out = confine_output('template', 'vars', 'common/.gitconfig', '-t', 'test')
+        assert 'MY_VAR' not in out
+        out = confine_output('template', 'vars', 'common/.gitconfig')
+        assert 'MY_VAR = "1"' in out
+
+        # nothing matches, -t is required
+        config.write_text("""
+[[templates.select]]
+host = "no-such-host-*"
+control = "test"
+""")
+        with pytest.raises(subprocess.CalledProcessError):
+            confine('ln', 'common')
+        confine('ln', 'common', '-t', 'test2')
+    finally:
+        config.unlink()
+
+
+def test_templates_vars():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    gitconfig = Path(home_test, '.gitconfig')
+    config = Path(tune, 'confine.toml')
+    control = Path(tune, 'templates', 'layers.toml')
+    try:
+        # global vars fill in what test.toml misses
+        config.write_text("""
+[templates.vars]
+MY_VAR = "global"
+""")
+        confine('ln', 'common', '-t', 'test')
+        assert gitconfig.read_text().splitlines()[1] == 'global'
+
+        control.write_text("""
+[_defaults]
+MY_VAR = "defaults"
+
+["common/.gitconfig"]
+""")
+        gitconfig.unlink()
+        confine('ln', 'common', '-t', 'layers')
+        assert gitconfig.read_text().splitlines()[1] == 'defaults'
+
+        control.write_text("""
+[_defaults]
+MY_VAR = "defaults"
+
+["common/.gitconfig"]
+MY_VAR = "file"
+""")
+        confine('ln', 'common', '-t', 'layers')
+        assert gitconfig.read_text().splitlines()[1] == 'file'
+
+        os.environ['CONFINE_VAR_MY_VAR'] = 'env'
+        try:
+            confine('ln', 'common', '-t', 'layers')
+            assert gitconfig.read_text().splitlines()[1] == 'env'
+            out = confine_output('template', 'vars', 'common/.gitconfig', '-t', 'layers')
+        finally:
+            del os.environ['CONFINE_VAR_MY_VAR']
+        lines = out.splitlines()
+        assert 'MY_VAR = "env"  # environment' in lines
+        assert 'HOME = "%s"  # built-in' % home_test in lines
+
+        out = confine_output('template', 'vars', 'common/.gitconfig', '-t', 'layers')
+        assert 'MY_VAR = "file"  # tune/templates/layers.toml ["common/.gitconfig"]' in out.splitlines()
+    finally:
+        config.unlink()
+        control.unlink()
+
+
+def test_templates_types():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    test_conf.write_text("""{% if WORK %}work{% endif %}
+{{ PORT + 1 }}
+{% for h in HOSTS %}{{ h }} {% endfor %}
+{{ USER.name }}
+{{ RELEASED }} {{ DATES.0 }} {{ T.at }}
+""")
+    confine('mv', 'common', '.test_conf')
+    control = Path(tune, 'templates', 'types.toml')
+    try:
+        control.write_text("""
+["common/.test_conf"]
+WORK = true
+PORT = 8080
+HOSTS = ["a", "b"]
+USER = { name = "nikita" }
+RELEASED = 1979-05-27T07:32:00Z
+DATES = [1979-05-27]
+T = { at = 07:32:00 }
+""")
+        confine('ln', 'common', '.test_conf', '-t', 'types')
+        assert test_conf.read_text().splitlines() == ['work', '8081', 'a b ', 'nikita', '1979-05-27T07:32:00Z 1979-05-27 07:32:00']
+    finally:
+        control.unlink()
+
+
+def test_templates_builtins():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    test_conf.write_text("""{{ GROUP }}
+{{ ROOT }}
+{{ UID }}
+{{ OS }}
+{{ ARCH }}
+{{ XDG_CONFIG_HOME }}
+{{ env.CONFINE_TEST }}
+{{ HOSTNAME }}
+{{ USER }}
+""")
+    confine('mv', 'common', '.test_conf')
+    control = Path(tune, 'templates', 'builtins.toml')
+    os.environ['CONFINE_TEST'] = 'from env'
+    xdg = os.environ.pop('XDG_CONFIG_HOME', None)
+    # as in cron, user comes from passwd
+    user = { k: os.environ.pop(k) for k in ['USER', 'LOGNAME'] if k in os.environ }
+    try:
+        control.write_text('["common/.test_conf"]\n')
+        confine('ln', 'common', '.test_conf', '-t', 'builtins')
+        lines = test_conf.read_text().splitlines()
+        assert lines[0] == 'common'
+        assert lines[1] == str(test_root)
+        assert lines[2] == str(os.getuid())
+        assert lines[3] == sys.platform.replace('darwin', 'macos')
+        assert lines[5] == str(Path(home_test, '.config'))
+        assert lines[6] == 'from env'
+        assert lines[7] != ''
+        assert lines[8] == pwd.getpwuid(os.getuid()).pw_name
+    finally:
+        del os.environ['CONFINE_TEST']
+        os.environ.update(user)
+        if xdg is not None:
+            os.environ['XDG_CONFIG_HOME'] = xdg
+        control.unlink()
+
+
+def test_templates_render():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    out = confine_output('template', 'render', 'common/.gitconfig', '-t', 'test2')
+    assert out.splitlines() == [str(home_test), '1']
+    assert not Path(processed_host, 'common/.gitconfig').exists()
+
+    out = confine_output('template', 'render', 'common/.gitconfig', '-t', 'test2', '--diff')
+    assert '+1' in out.splitlines()
+
+    confine('ln', 'common', '-t', 'test2')
+    out = confine_output('template', 'render', 'common/.gitconfig', '-t', 'test2', '--diff')
+    assert out == ''
+
+    with pytest.raises(subprocess.CalledProcessError):
+        # variable missing
+        confine('--strict', 'template', 'render', 'common/.gitconfig', '-t', 'test')
+
+
+def test_templates_errors():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    gitconfig = Path(home_test, '.gitconfig')
+
+    # undefined variable is empty with a warning
+    confine('ln', 'common', '-t', 'test')
+    assert gitconfig.read_text().splitlines() == [str(home_test), '']
+
+    # file, line and name of undefined variable
+    args = [str(confine_exe), '--home', str(home_test), '--strict', 'template', 'render', 'common/.gitconfig', '-t', 'test']
+    res = subprocess.run(args, capture_output=True, text=True)
+    assert res.returncode != 0
+    assert 'MY_VAR (%s:2:3)' % Path(common, ".gitconfig") in res.stderr
+
+    # syntax error
+    source = Path(common, ".gitconfig")
+    source.write_text('{{ HOME }}\n{% if %}\n')
+    args = [str(confine_exe), '--home', str(home_test), 'template', 'render', 'common/.gitconfig', '-t', 'test2']
+    res = subprocess.run(args, capture_output=True, text=True)
+    assert res.returncode != 0
+    assert '%s:2:7:' % source in res.stderr
+
+
+def test_templates_processed_dir():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    gitconfig = Path(home_test, '.gitconfig')
+
+    # per host in storage by default
+    confine('ln', 'common', '-t', 'test2')
+    assert gitconfig.resolve() == Path(processed_host, 'common/.gitconfig').resolve()
+
+    config = Path(tune, 'confine.toml')
+    try:
+        config.write_text('[templates]\nprocessed = "~/.local/state/confine"\n')
+        confine('ln', 'common', '-t', 'test2')
+        state = Path(home_test, '.local/state/confine')
+        assert gitconfig.resolve() == Path(state, socket.gethostname(), 'common/.gitconfig').resolve()
+        assert gitconfig.read_text().splitlines()[1] == '1'
+        assert Path(state, '.state.toml').exists()
+        confine('status', 'common')
+    finally:
+        config.unlink()
+
+
+def test_templates_check():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    confine('mv', 'common', '.test_conf')
+    confine('template', 'check')
+
+    control = Path(tune, 'templates/check.toml')
+    try:
+        control.write_text('[_defaults]\nUNUSED_DEFAULT = 1\n["common/.gitconfig"]\nMY_VAR = 1\nUNUSED = 2\n["common/.no_such_file"]\nX = 1\n')
+        # template without section in any control file
+        meta.write_text(meta.read_text().replace('path = ".test_conf"', 'path = ".test_conf"\ntemplate = true'))
+        args = [str(confine_exe), '--home', str(home_test), 'template', 'check']
+        res = subprocess.run(args, capture_output=True, text=True)
+        assert res.returncode != 0
+        lines = res.stdout.splitlines()
+        assert 'tune/templates/check.toml: ["common/.no_such_file"] is not in meta of any group' in lines
+        assert 'tune/templates/check.toml: ["common/.gitconfig"] variable UNUSED is not used' in lines
+        assert 'tune/templates/check.toml: ["_defaults"] variable UNUSED_DEFAULT is not used' in lines
+        assert 'common/.test_conf: template has no section in any control file' in lines
+        assert len(lines) == 5 and lines[-1] == "4 problem(s) found"
+
+        # syntax errors are reported, not panics
+        control.write_text('["common/.gitconfig"]\nMY_VAR = \n')
+        res = subprocess.run(args, capture_output=True, text=True)
+        assert res.returncode == 1
+        assert 'check.toml' in res.stdout
+
+        control.write_text('"common/.gitconfig" = 1\n')
+        res = subprocess.run(args, capture_output=True, text=True)
+        assert res.returncode == 1
+        assert 'must be a table of variables' in res.stdout
+        res = subprocess.run([str(confine_exe), '--home', str(home_test), 'status'], capture_output=True, text=True)
+        assert res.returncode == 1
+        assert "must be a table of variables" in res.stdout + res.stderr
+
+        # missing template file is one more problem, not an abort
+        control.unlink()
+        Path(common, '.gitconfig').rename(Path(home_test, 'gitconfig'))
+        res = subprocess.run(args, capture_output=True, text=True)
+        assert res.returncode == 1
+        lines = res.stdout.splitlines()
+        assert [l for l in lines if l.startswith('tune/templates/test.toml: ["common/.gitconfig"]')]
+        assert [l for l in lines if l.startswith('tune/templates/test2.toml: ["common/.gitconfig"]')]
+        assert 'common/.test_conf: template has no section in any control file' in lines
+    finally:
+        if control.exists():
+            control.unlink()
+
+
+def test_templates_refresh():
+    setup()
+
+    confine('mv', 'common', '.gitconfig')
+    gitconfig = Path(home_test, '.gitconfig')
+    control = Path(tune, 'templates', 'refresh.toml')
+    try:
+        control.write_text('["common/.gitconfig"]\nMY_VAR = "1"\n')
+        confine('ln', 'common', '-t', 'refresh')
+        confine('status')
+        confine('template', 'refresh')
+        assert gitconfig.read_text().splitlines()[1] == '1'
+
+        control.write_text('["common/.gitconfig"]\nMY_VAR = "2"\n')
+        with pytest.raises(subprocess.CalledProcessError):
+            confine('status')
+        out = confine_output('template', 'refresh')
+        assert out.splitlines() == ['refreshed common/.gitconfig']
+        assert gitconfig.read_text().splitlines()[1] == '2'
+        confine('status')
+
+        # template itself changed
+        Path(common, '.gitconfig').write_text('{{ MY_VAR }}\n')
+        with pytest.raises(subprocess.CalledProcessError):
+            confine('status')
+        confine('template', 'refresh')
+        assert gitconfig.read_text() == '2\n'
+        confine('status')
+    finally:
+        control.unlink()
+
+
+def test_templates_partials():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    test_conf.write_text("""{% import "macros/kv.tera" as kv %}{% include "proxy" %}
+{{ kv::pair(k="a", v=MY_VAR) }}
+""")
+    confine('mv', 'common', '.test_conf')
+    partials = Path(tune, 'templates', 'partials')
+    control = Path(tune, 'templates', 'partials.toml')
+    try:
+        Path(partials, 'macros').mkdir(parents=True)
+        Path(partials, 'proxy').write_text('proxy={{ MY_VAR }}')
+        Path(partials, 'macros/kv.tera').write_text('{% macro pair(k, v) %}{{ k }} = {{ v }}{% endmacro pair %}')
+        control.write_text('["common/.test_conf"]\nMY_VAR = "<1>"\n')
+        confine('ln', 'common', '.test_conf', '-t', 'partials')
+        assert test_conf.read_text().splitlines() == ['proxy=<1>', 'a = <1>']
+
+        # partials are inputs of processed file too
+        Path(partials, 'proxy').write_text('proxy: {{ MY_VAR }}')
+        with pytest.raises(subprocess.CalledProcessError):
+            confine('status')
+        confine('template', 'refresh')
+        assert test_conf.read_text().splitlines()[0] == 'proxy: <1>'
+    finally:
+        rmtree(partials)
+        control.unlink()
+
+
+def test_templates_functions():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    test_conf.write_text("""{{ command_exists(name="sh") }} {{ command_exists(name="no-such-command-x") }}
+{{ file_exists(path="~/.config/test_file") }} {{ file_exists(path="/no/such/file") }}
+{{ read_file(path=".config/test_file") }}
+{{ env(name="CONFINE_TEST", default="unset") }} {{ env(name="CONFINE_NO_SUCH_VAR", default="default") }}
+{{ path_join(parts=["a", "b", "c"]) }}
+{{ "it's" | shell_quote }}
+""")
+    Path(home_test, '.config/test_file').write_text('content')
+    confine('mv', 'common', '.test_conf')
+    control = Path(tune, 'templates', 'functions.toml')
+    os.environ['CONFINE_TEST'] = 'set'
+    try:
+        control.write_text('["common/.test_conf"]\n')
+        confine('ln', 'common', '.test_conf', '-t', 'functions')
+        assert test_conf.read_text().splitlines() == [
+            'true false',
+            'true false',
+            'content',
+            'set default',
+            'a/b/c',
+            "'it'\\''s'",
+        ]
+    finally:
+        del os.environ['CONFINE_TEST']
+        control.unlink()
+
+
+def test_templates_secrets():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    test_conf.write_text('{{ FILE }} {{ ENV }} {{ AUTH.token }}\n')
+    confine('mv', 'common', '.test_conf')
+    secrets = Path(home_test, '.config/confine/secrets.toml')
+    secrets.parent.mkdir(parents=True)
+    secrets.write_text('token = "from-file"\n')
+    control = Path(tune, 'templates', 'secrets.toml')
+    os.environ['CONFINE_TEST_SECRET'] = 'from-env'
+    try:
+        control.write_text("""["common/.test_conf"]
+FILE = { secret = "token" }
+ENV = { secret_env = "CONFINE_TEST_SECRET" }
+AUTH = { token = { secret_command = "echo from-command" } }
+""")
+        confine('ln', 'common', '.test_conf', '-t', 'secrets')
+        assert test_conf.read_text() == 'from-file from-env from-command\n'
+        assert Path(processed_host, 'common/.test_conf').stat().st_mode & 0o777 == 0o600
+        assert Path(processed, '.gitignore').read_text() == '/%s/common/.test_conf\n' % socket.gethostname()
+
+        out = confine_output('template', 'vars', 'common/.test_conf', '-t', 'secrets')
+        assert 'from-file' not in out and 'from-command' not in out and 'from-env' not in out
+        assert 'env = "***"  # built-in, secret' in out.splitlines()
+        assert 'FILE = "***"  # tune/templates/secrets.toml ["common/.test_conf"], secret' in out.splitlines()
+
+        control.write_text('["common/.test_conf"]\nFILE = { secret = "no_such_secret" }\n')
+        with pytest.raises(subprocess.CalledProcessError):
+            confine('ln', 'common', '.test_conf', '-t', 'secrets')
+    finally:
+        del os.environ['CONFINE_TEST_SECRET']
+        control.unlink()
+
+
+def test_templates_secrets_delete():
+    setup()
+
+    test_conf = Path(home_test, '.test_conf')
+    test_conf.write_text('{{ TOKEN }}\n')
+    confine('mv', 'common', '.test_conf')
+    control = Path(tune, 'templates', 'secrets.toml')
+    os.environ['CONFINE_TEST_SECRET'] = 'hunter2'
+    try:
+        control.write_text('["common/.test_conf"]\nTOKEN = { secret_env = "CONFINE_TEST_SECRET" }\n')
+        confine('ln', 'common', '.test_conf', '-t', 'secrets')
+        assert test_conf.read_text() == 'hunter2\n'
+        confine('rm', '-y', 'common', '.test_conf')
+        assert not Path(processed_host, 'common/.test_conf').exists()
+        assert [f for f in journal.rglob('*') if f.is_file() and b'hunter2' in f.read_bytes()] == []
+    finally:
+        del os.environ['CONFINE_TEST_SECRET']
+        control.unlink()
+
+
+def test_encrypt():
+    setup()
+
+    netrc = Path(home_test, '.netrc')
+    netrc.write_text('machine example.com password secret\n')
+    key = Path(home_test, '.config/confine/key')
+
+    confine('-n', 'mv', '--encrypt', 'common', '.netrc')
+    assert not key.exists()
+    assert not netrc.is_symlink()
+
+    confine('mv', '--encrypt', 'common', '.netrc')
+    stored = Path(common, '.netrc').read_text()
+    assert stored.startswith('-----BEGIN CONFINE ENCRYPTED FILE-----')
+    assert 'secret' not in stored
+    assert key.stat().st_mode & 0o777 == 0o600
+    assert netrc.is_symlink()
+    assert netrc.read_text() == 'machine example.com password secret\n'
+    assert netrc.stat().st_mode & 0o777 == 0o600
+    with open(meta, 'rb') as f:
+        assert tomllib.load(f)['files'] == [{'path': '.netrc', 'encrypted': True}]
+    confine('status')
+
+    # linked from scratch
+    netrc.unlink()
+    rmtree(processed)
+    confine('ln', 'common')
+    assert netrc.read_text() == 'machine example.com password secret\n'
+
+    # other machine, no key
+    netrc.unlink()
+    key.rename(Path(home_test, 'key'))
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('ln', 'common')
+    Path(home_test, 'key').rename(key)
+    confine('ln', 'common')
+
+    confine('undo', 'common')
+    assert not netrc.is_symlink()
+    assert netrc.read_text() == 'machine example.com password secret\n'
+
+
+def test_broken_config():
+    setup()
+
+    netrc = Path(home_test, '.netrc')
+    netrc.write_text('machine example.com password secret\n')
+    config = Path(tune, 'confine.toml')
+    try:
+        config.write_text('[encryption]\nkey = "~/.secrets/key"\n[[templates.select\n')
+        res = subprocess.run([str(confine_exe), '--home', str(home_test), 'mv', '--encrypt', 'common', '.netrc'], capture_output=True, text=True)
+        assert res.returncode != 0
+        assert 'confine.toml' in res.stdout + res.stderr
+        assert not netrc.is_symlink()
+        assert not Path(home_test, '.config/confine/key').exists()
+    finally:
+        config.unlink()
+
+
+def test_encrypt_no_plaintext_kept():
+    setup()
+
+    netrc = Path(home_test, '.netrc')
+    netrc.write_text('machine example.com password secret\n')
+
+    def kept():
+        return [f for d in [journal, backup] if d.exists() for f in d.rglob('*')
+                if f.is_file() and b'secret' in f.read_bytes()]
+
+    confine('mv', '--encrypt', 'common', '.netrc')
+    assert kept() == []
+
+    # overwritten home file is backed up encrypted
+    replace_link(netrc, 'machine example.com password other secret\n')
+    confine('ln', 'common')
+    assert kept() == []
+    assert netrc.read_text() == 'machine example.com password secret\n'
+
+    netrc.unlink()
+    confine('restore-backup', '.netrc')
+    assert netrc.read_text() == 'machine example.com password other secret\n'
+    assert netrc.stat().st_mode & 0o777 == 0o600
+
+    confine('rm', '-y', 'common', '.netrc')
+    assert kept() == []
+
+
 #########
 
 def test_undo():
@@ -245,8 +1321,90 @@ def test_undo():
     assert not test_file.is_symlink()
 
 
+def test_undo_template():
+    setup()
+
+    gitconfig = Path(home_test, '.gitconfig')
+    confine('mv', 'common', '.gitconfig')
+    confine('ln', 'common', '-t', 'test2')
+    confine('undo', 'common')
+    assert not gitconfig.is_symlink()
+    assert gitconfig.read_text().splitlines() == [str(home_test), '1']
+
+    # linked to the template itself, rendered on undo
+    gitconfig.unlink()
+    gitconfig.symlink_to(Path(common, '.gitconfig'))
+    rmtree(processed)
+    confine('undo', 'common', '-t', 'test2')
+    assert not gitconfig.is_symlink()
+    assert gitconfig.read_text().splitlines() == [str(home_test), '1']
+
+
 #############
 
+def test_status():
+    setup()
+
+    confine('mv', 'common', '.test_conf', '.test_dir', '.config/test_file')
+    confine('status')
+    confine('status', 'common', '.test_conf')
+
+    Path(home_test, '.test_conf').unlink()
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('status')
+    confine('status', 'common', '.test_dir', '.config/test_file')
+
+    Path(home_test, '.test_conf').write_text('oh shi')
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('status', 'common/.test_conf')
+
+    rmtree(Path(common, '.test_dir'))
+    with pytest.raises(subprocess.CalledProcessError):
+        confine('status', 'common/.test_dir')
+
+
+#############
+
+def test_delete_template():
+    setup()
+
+    # not .gitconfig, test control files have sections for it
+    test_conf = Path(home_test, '.test_conf')
+    test_conf.write_text('{{ MY_VAR }}\n')
+    confine('mv', 'common', '.test_conf')
+    control = Path(tune, 'templates', 'delete.toml')
+    try:
+        control.write_text("""["common/.test_conf"]
+MY_VAR = "1"
+
+# keep me
+["common/.other"]
+X = "y"
+""")
+        confine('ln', 'common', '.test_conf', '-t', 'delete')
+        assert Path(processed_host, 'common/.test_conf').exists()
+
+        # not confirmed
+        confine_output('rm', 'common', '.test_conf', input='n\n')
+        assert not Path(common, '.test_conf').exists()
+        assert not Path(processed_host, 'common/.test_conf').exists()
+        assert '["common/.test_conf"]' in control.read_text()
+
+        setup()
+        test_conf.write_text('{{ MY_VAR }}\n')
+        confine('mv', 'common', '.test_conf')
+        confine('ln', 'common', '.test_conf', '-t', 'delete')
+        confine('rm', '-y', 'common', '.test_conf')
+        assert control.read_text() == """
+# keep me
+["common/.other"]
+X = "y"
+"""
+        assert '.test_conf' not in get_meta()
+    finally:
+        control.unlink()
+
+
 def test_delete1():
     setup();
 